@@ -11,6 +11,6 @@ echo "Nginx started!"
 
 # If you have another command, replace the echo with your command
 echo "Nginx is running in the background. Starting your app..."
-rocks_svr;
+rocks_svr run --config /etc/rocks/rocks.toml;
 
 ps -A
@@ -84,7 +84,7 @@ pub trait BufferFormer {
 pub(crate) trait LocalProtocol {
     async fn handle(
         &self,
-        connection: impl AsyncRead + AsyncWrite + Send + Sync + Unpin,
+        connection: impl AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
         remote_addr: SocketAddr,
     ) -> Result<(), Error>;
 }
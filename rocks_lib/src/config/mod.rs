@@ -0,0 +1,1136 @@
+// Server configuration, loaded from a TOML file and validated with
+// `serde_valid` before anything is started.
+//
+// ```toml
+// [[inbounds]]
+// tag = "vless-tcp"
+// listen = "127.0.0.1:34434"
+// transport = { type = "tcp" }
+// protocol = "vless"
+// proxy_protocol = { from = ["10.0.0.0/8"] }
+// tls = { cert = "cert.pem", key = "key.pem", alpn = ["http/1.1"] }
+//
+// [[inbounds.fallbacks]]
+// path = "/api"
+// dest = "8080"
+// proxy_protocol = 1
+//
+// [[inbounds.fallbacks]]
+// dest = "site"
+//
+// [[inbounds]]
+// tag = "vless-ws"
+// listen = "0.0.0.0:80"
+// transport = { type = "ws", path = "/vless" }
+//
+// [[inbounds.fallbacks]]
+// dest = "site"
+//
+// [[inbounds.routes]]
+// host = "admin.example.com"
+// users = ["test"]
+// max_connections = 16
+//
+// [[inbounds]]
+// tag = "vless-xhttp"
+// listen = "0.0.0.0:8080"
+// transport = { type = "splithttp", path = "/xhttp" }
+//
+// [[inbounds]]
+// tag = "vless-quic"
+// listen = "0.0.0.0:443"
+// transport = { type = "quic" }
+// tls = { cert = "cert.pem", key = "key.pem", alpn = ["vless"] }
+//
+// [[inbounds]]
+// tag = "vless-grpc"
+// listen = "0.0.0.0:443"
+// transport = { type = "grpc", service_name = "GunService" }
+// tls = { cert = "cert.pem", key = "key.pem", alpn = ["h2"] }
+//
+// [[inbounds]]
+// tag = "vless-h2"
+// listen = "0.0.0.0:8443"
+// transport = { type = "h2", host = ["example.com"], path = "/h2" }
+// tls = { cert = "cert.pem", key = "key.pem", alpn = ["h2"] }
+//
+// [[inbounds]]
+// tag = "vless-unix"
+// listen = "unix:/run/rocks/vless.sock"
+// listen_mode = 0o660
+// transport = { type = "ws", path = "/vless" }
+//
+// [[users]]
+// id = "test"
+// flows = [""]
+//
+// [site]
+// listen = "127.0.0.1:8888"
+// root = "public"
+// ```
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Error};
+use derive_more::derive::Display;
+use serde::{Deserialize, Deserializer};
+use serde_valid::{toml::FromTomlStr, Validate};
+use uuid::Uuid;
+
+use crate::{user_id_from_str, ProxyVersion, VlessUser, VlessUsers, FLOW_NONE, SUPPORTED_FLOWS};
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+#[validate(custom = |c| validate_unique_inbounds(&c.inbounds))]
+#[validate(custom = validate_fallbacks)]
+#[validate(custom = validate_routes)]
+#[validate(custom = validate_quic)]
+#[validate(custom = validate_h2)]
+pub struct Config {
+    #[validate(min_items = 1)]
+    #[validate]
+    pub inbounds: Vec<InboundConfig>,
+    #[validate(min_items = 1)]
+    #[validate]
+    pub users: Vec<UserConfig>,
+    #[validate]
+    pub site: Option<SiteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+#[validate(custom = validate_listen)]
+pub struct InboundConfig {
+    #[validate(min_length = 1)]
+    pub tag: String,
+    pub listen: ListenAddr,
+    /// Permissions of a Unix socket `listen`, such as `0o660` to let only
+    /// the group of the front server connect.
+    pub listen_mode: Option<u32>,
+    #[serde(default)]
+    #[validate]
+    pub transport: InboundTransport,
+    #[serde(default)]
+    pub protocol: InboundProtocol,
+    /// Expect a PROXY protocol header on connections from load balancers.
+    #[validate]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    /// Send a PROXY header telling the client address to the TCP targets
+    /// it names.
+    #[validate]
+    pub outbound_proxy_protocol: Option<OutboundProxyProtocolConfig>,
+    /// Terminate TLS on accepted connections.
+    #[validate]
+    pub tls: Option<TlsConfig>,
+    /// Where to send connections that do not start with a valid VLESS
+    /// request, first match wins.
+    #[serde(default)]
+    #[validate]
+    pub fallbacks: Vec<FallbackConfig>,
+    /// Rules picking the settings of a WebSocket session from its upgrade
+    /// request, first match wins. Requests matching none get the defaults.
+    #[serde(default)]
+    #[validate]
+    pub routes: Vec<WsRouteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// The load balancers, which must send a version 1 or 2 header. Other
+    /// peers are served as they are, without one.
+    #[validate(min_items = 1)]
+    pub from: Vec<IpCidr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundProxyProtocolConfig {
+    /// The PROXY protocol version, `1` or `2`.
+    pub version: ProxyVersion,
+    /// The targets told, which must expect the header. Any other target
+    /// gets the connection as it is.
+    #[validate(min_items = 1)]
+    pub to: Vec<IpCidr>,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM file holding the private key.
+    pub key: PathBuf,
+    /// ALPN protocols offered to clients, in order of preference.
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Validate)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum InboundTransport {
+    #[default]
+    Tcp,
+    Ws(#[validate] WsConfig),
+    /// The WebSocket handshake without WebSocket framing after it.
+    HttpUpgrade(#[validate] WsConfig),
+    Grpc(#[validate] GrpcConfig),
+    /// Xray's h2 transport: a stream in the bodies of an HTTP/2 request and
+    /// its response. Needs `tls` offering `h2`.
+    H2(#[validate] H2Config),
+    #[serde(alias = "xhttp")]
+    SplitHttp(#[validate] SplitHttpConfig),
+    /// A QUIC listener, which needs `tls`. Each stream is a VLESS session.
+    Quic,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct WsConfig {
+    /// Only upgrade requests for this path, handing every other request to
+    /// the fallbacks. Without it every request is upgraded.
+    #[validate(pattern = "^/")]
+    pub path: Option<String>,
+    /// Also take 0-RTT early data from this query parameter, for clients
+    /// that cannot put it in `Sec-WebSocket-Protocol`.
+    pub early_data_query: Option<String>,
+    /// Reverse proxies whose word on the client address is taken.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpCidr>,
+    /// The header trusted proxies put the client address in, either a list
+    /// like `X-Forwarded-For` or a single address like `X-Real-IP`.
+    #[serde(default = "default_real_ip_header")]
+    pub real_ip_header: String,
+}
+
+fn default_real_ip_header() -> String {
+    "X-Forwarded-For".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// The service whose `Tun` and `TunMulti` methods clients call.
+    #[serde(default = "default_service_name")]
+    #[validate(pattern = "^[^/]+$")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "GunService".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct H2Config {
+    /// Hosts requests may be for, any of them if empty.
+    #[serde(default)]
+    pub host: Vec<String>,
+    /// The path of the requests, other requests are answered with a 404.
+    #[serde(default = "default_h2_path")]
+    #[validate(pattern = "^/")]
+    pub path: String,
+    /// The method of the requests, `PUT` or `POST` if not set.
+    pub method: Option<String>,
+}
+
+fn default_h2_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SplitHttpConfig {
+    /// Sessions are served under this path, anything else goes to the
+    /// fallbacks.
+    #[serde(default = "default_split_http_path")]
+    #[validate(pattern = "^/")]
+    pub path: String,
+    /// The largest chunk a single POST may upload.
+    #[serde(default = "default_max_post_bytes")]
+    #[validate(minimum = 1)]
+    pub max_post_bytes: usize,
+    /// How many chunks may arrive ahead of the next one in order.
+    #[serde(default = "default_max_buffered_posts")]
+    #[validate(minimum = 1)]
+    pub max_buffered_posts: usize,
+    /// How many sessions may wait for their download at once; requests
+    /// starting more are answered with a 429.
+    #[serde(default = "default_max_pending_sessions")]
+    #[validate(minimum = 1)]
+    pub max_pending_sessions: usize,
+    /// Reverse proxies whose word on the client address is taken, as for ws.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpCidr>,
+    #[serde(default = "default_real_ip_header")]
+    pub real_ip_header: String,
+}
+
+impl Default for SplitHttpConfig {
+    fn default() -> Self {
+        Self {
+            path: default_split_http_path(),
+            max_post_bytes: default_max_post_bytes(),
+            max_buffered_posts: default_max_buffered_posts(),
+            max_pending_sessions: default_max_pending_sessions(),
+            trusted_proxies: vec![],
+            real_ip_header: default_real_ip_header(),
+        }
+    }
+}
+
+fn default_split_http_path() -> String {
+    "/".to_string()
+}
+
+fn default_max_post_bytes() -> usize {
+    1_000_000
+}
+
+fn default_max_buffered_posts() -> usize {
+    30
+}
+
+fn default_max_pending_sessions() -> usize {
+    128
+}
+
+/// Where an inbound listens: an address such as `0.0.0.0:443`, or a Unix
+/// socket such as `unix:/run/rocks/vless.sock`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Display, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    #[display("{_0}")]
+    Inet(SocketAddr),
+    #[display("unix:{}", _0.display())]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty Unix socket path".to_string()),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Self::Inet)
+                .map_err(|_| format!("invalid listen address `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A range of IP addresses such as `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpCidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        let masked = |bits: u128, width: u8| {
+            let shift = width - self.prefix;
+            bits.checked_shr(shift.into()).unwrap_or(0)
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                masked(u32::from(net).into(), 32) == masked(u32::from(addr).into(), 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                masked(net.into(), 128) == masked(addr.into(), 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpCidr {
+    type Error = String;
+
+    fn try_from(cidr: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid address range `{}`", cidr);
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr.as_str(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => width,
+        };
+        if prefix > width {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// Match the server name the client sent with SNI.
+    pub sni: Option<String>,
+    /// Match the protocol negotiated with ALPN.
+    pub alpn: Option<String>,
+    /// Match HTTP requests whose path starts with this.
+    #[validate(pattern = "^/")]
+    pub path: Option<String>,
+    pub dest: FallbackDest,
+    /// Send a PROXY header of this version, `1` or `2`, to `dest` first.
+    pub proxy_protocol: Option<ProxyVersion>,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct WsRouteConfig {
+    /// Match requests for this path or anything under it: `/a` matches `/a`
+    /// and `/a/b`, not `/admin`.
+    #[validate(pattern = "^/")]
+    pub path: Option<String>,
+    /// Match the Host header, ignoring case and any port.
+    pub host: Option<String>,
+    /// Match requests carrying all of these headers with exactly these
+    /// values. Names are compared ignoring case.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Answer matching requests with a 404 instead of upgrading them.
+    #[serde(default)]
+    pub reject: bool,
+    /// Only accept these users on matching sessions, instead of all of them.
+    #[serde(default, deserialize_with = "deserialize_user_ids")]
+    pub users: Option<Vec<Uuid>>,
+    /// Send a PROXY header to the TCP targets of matching sessions as this
+    /// says, in place of the inbound's setting.
+    #[validate]
+    pub outbound_proxy_protocol: Option<OutboundProxyProtocolConfig>,
+    /// Refuse matching sessions with a 503 while this many are open.
+    #[validate(minimum = 1)]
+    pub max_connections: Option<usize>,
+}
+
+/// Where a fallback connection goes: `"site"` for the built-in site, a port
+/// on localhost, `host:port`, or the path of a Unix socket.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FallbackDest {
+    Site,
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for FallbackDest {
+    type Error = String;
+
+    fn try_from(dest: String) -> Result<Self, Self::Error> {
+        if dest == "site" {
+            Ok(Self::Site)
+        } else if dest.starts_with('/') {
+            Ok(Self::Unix(PathBuf::from(dest)))
+        } else if let Ok(port) = dest.parse::<u16>() {
+            Ok(Self::Tcp(format!("127.0.0.1:{}", port)))
+        } else if dest
+            .rsplit_once(':')
+            .is_some_and(|(_, p)| p.parse::<u16>().is_ok())
+        {
+            Ok(Self::Tcp(dest))
+        } else {
+            Err(format!("invalid fallback destination `{}`", dest))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InboundProtocol {
+    #[default]
+    Vless,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// A UUID, or any other string of up to 30 bytes mapped to a UUID the
+    /// same way Xray does.
+    #[serde(deserialize_with = "deserialize_user_id")]
+    pub id: Uuid,
+    /// The flows the user may request, `""` meaning plain VLESS.
+    #[serde(default = "default_flows")]
+    #[validate(custom = validate_flows)]
+    pub flows: Vec<String>,
+}
+
+fn default_flows() -> Vec<String> {
+    vec![FLOW_NONE.to_string()]
+}
+
+fn validate_flows(flows: &[String]) -> Result<(), serde_valid::validation::Error> {
+    match flows
+        .iter()
+        .find(|f| !SUPPORTED_FLOWS.contains(&f.as_str()))
+    {
+        Some(flow) => Err(serde_valid::validation::Error::Custom(format!(
+            "unsupported flow `{}`",
+            flow
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn deserialize_user_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+    let id = String::deserialize(deserializer)?;
+    user_id_from_str(&id).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_user_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<Uuid>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|id| user_id_from_str(id).map_err(serde::de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SiteConfig {
+    /// Serve the site on a listener of its own. Without it the site is only
+    /// reachable through inbound fallbacks.
+    pub listen: Option<SocketAddr>,
+    #[serde(default = "default_site_root")]
+    pub root: PathBuf,
+}
+
+fn default_site_root() -> PathBuf {
+    PathBuf::from("public")
+}
+
+fn validate_unique_inbounds(
+    inbounds: &[InboundConfig],
+) -> Result<(), serde_valid::validation::Error> {
+    let mut tags = HashSet::new();
+    let mut listens = HashSet::new();
+    for inbound in inbounds {
+        if !tags.insert(inbound.tag.as_str()) {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "duplicate inbound tag `{}`",
+                inbound.tag
+            )));
+        }
+        // QUIC listens on UDP, so it may share a port with a TCP inbound.
+        let udp = matches!(inbound.transport, InboundTransport::Quic);
+        if !listens.insert((udp, &inbound.listen)) {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "inbound `{}` listens on {} which is already in use",
+                inbound.tag, inbound.listen
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_listen(inbound: &InboundConfig) -> Result<(), serde_valid::validation::Error> {
+    let error = |message: &str| {
+        Err(serde_valid::validation::Error::Custom(format!(
+            "inbound `{}`: {}",
+            inbound.tag, message
+        )))
+    };
+    match (&inbound.listen, inbound.listen_mode) {
+        (ListenAddr::Inet(_), Some(_)) => error("listen_mode needs a Unix socket to listen on"),
+        (_, Some(mode)) if mode > 0o777 => error("listen_mode takes permission bits only"),
+        _ => Ok(()),
+    }
+}
+
+fn validate_quic(config: &Config) -> Result<(), serde_valid::validation::Error> {
+    for inbound in &config.inbounds {
+        if !matches!(inbound.transport, InboundTransport::Quic) {
+            continue;
+        }
+        let error = |message: &str| {
+            Err(serde_valid::validation::Error::Custom(format!(
+                "inbound `{}`: {}",
+                inbound.tag, message
+            )))
+        };
+        if inbound.tls.is_none() {
+            return error("quic needs tls");
+        }
+        if inbound.proxy_protocol.is_some() {
+            return error("quic cannot take proxy_protocol headers");
+        }
+        if matches!(inbound.listen, ListenAddr::Unix(_)) {
+            return error("quic cannot listen on a Unix socket");
+        }
+    }
+    Ok(())
+}
+
+fn validate_h2(config: &Config) -> Result<(), serde_valid::validation::Error> {
+    for inbound in &config.inbounds {
+        if !matches!(inbound.transport, InboundTransport::H2(_)) {
+            continue;
+        }
+        if !inbound
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.alpn.iter().any(|p| p == "h2"))
+        {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "inbound `{}`: h2 needs tls with `h2` in its alpn",
+                inbound.tag
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_fallbacks(config: &Config) -> Result<(), serde_valid::validation::Error> {
+    for inbound in &config.inbounds {
+        let uses_site = inbound
+            .fallbacks
+            .iter()
+            .any(|f| f.dest == FallbackDest::Site);
+        if uses_site && config.site.is_none() {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "inbound `{}` falls back to the site but no [site] is configured",
+                inbound.tag
+            )));
+        }
+        let site_relayed = inbound
+            .fallbacks
+            .iter()
+            .any(|f| f.dest == FallbackDest::Site && f.proxy_protocol.is_some());
+        if site_relayed {
+            return Err(serde_valid::validation::Error::Custom(format!(
+                "inbound `{}`: the site fallback takes no proxy_protocol",
+                inbound.tag
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_routes(config: &Config) -> Result<(), serde_valid::validation::Error> {
+    for inbound in &config.inbounds {
+        if inbound.routes.is_empty() {
+            continue;
+        }
+        let error = |message: String| {
+            Err(serde_valid::validation::Error::Custom(format!(
+                "inbound `{}`: {}",
+                inbound.tag, message
+            )))
+        };
+        if !matches!(
+            inbound.transport,
+            InboundTransport::Ws(_) | InboundTransport::HttpUpgrade(_)
+        ) {
+            return error("routes need the ws or httpupgrade transport".to_string());
+        }
+        for route in &inbound.routes {
+            let users = route.users.as_deref().unwrap_or_default();
+            let settings = !users.is_empty()
+                || route.outbound_proxy_protocol.is_some()
+                || route.max_connections.is_some();
+            if route.reject && settings {
+                return error("a rejecting route takes no session settings".to_string());
+            }
+            if let Some(id) = users
+                .iter()
+                .find(|id| !config.users.iter().any(|u| u.id == **id))
+            {
+                return error(format!("route names unknown user {}", id));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Read and validate the configuration file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("loading config {}", path.display()))
+    }
+
+    /// Parse and validate a configuration from a TOML string.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        Ok(Self::from_toml_str(content)?)
+    }
+
+    /// Replace the listen address of the inbound tagged `tag`.
+    pub fn override_listen(&mut self, tag: &str, listen: ListenAddr) -> Result<(), Error> {
+        let inbound = self
+            .inbounds
+            .iter_mut()
+            .find(|i| i.tag == tag)
+            .ok_or_else(|| anyhow!("no inbound tagged `{}`", tag))?;
+        inbound.listen = listen;
+        Ok(validate_unique_inbounds(&self.inbounds)?)
+    }
+
+    /// The users accepted by every inbound.
+    pub fn vless_users(&self) -> VlessUsers {
+        VlessUsers::new(
+            self.users
+                .iter()
+                .map(|u| VlessUser::new(u.id).with_flows(u.flows.iter().cloned())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FLOW_VISION;
+
+    const EXAMPLE: &str = r#"
+        [[inbounds]]
+        tag = "vless-tcp"
+        listen = "127.0.0.1:34434"
+
+        [[inbounds]]
+        tag = "vless-ws"
+        listen = "127.0.0.1:34080"
+        transport = { type = "ws" }
+        protocol = "vless"
+        tls = { cert = "cert.pem", key = "key.pem", alpn = ["http/1.1"] }
+
+        [[users]]
+        id = "test"
+
+        [site]
+        listen = "127.0.0.1:8888"
+    "#;
+
+    #[test]
+    fn test_parse_example() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.inbounds.len(), 2);
+        assert!(matches!(
+            config.inbounds[0].transport,
+            InboundTransport::Tcp
+        ));
+        assert!(matches!(
+            config.inbounds[1].transport,
+            InboundTransport::Ws(WsConfig { path: None, .. })
+        ));
+        assert!(config.inbounds[0].tls.is_none());
+        let tls = config.inbounds[1].tls.as_ref().unwrap();
+        assert_eq!(tls.cert, PathBuf::from("cert.pem"));
+        assert_eq!(tls.alpn, vec!["http/1.1".to_string()]);
+        assert_eq!(config.site.as_ref().unwrap().root, PathBuf::from("public"));
+        assert_eq!(config.vless_users().len(), 1);
+    }
+
+    #[test]
+    fn test_user_flows() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.users[0].flows, vec![String::new()]);
+        let content = EXAMPLE.replace(
+            "id = \"test\"",
+            "id = \"test\"\nflows = [\"xtls-rprx-vision\"]",
+        );
+        let config = Config::parse(&content).unwrap();
+        assert_eq!(config.users[0].flows, vec![FLOW_VISION.to_string()]);
+        let content = content.replace("xtls-rprx-vision", "xtls-rprx-direct");
+        assert!(Config::parse(&content).is_err());
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let tcp = "listen = \"127.0.0.1:34434\"\n";
+        let content = EXAMPLE.replacen(
+            tcp,
+            &format!(
+                "{}{}",
+                tcp,
+                r#"
+        [[inbounds.fallbacks]]
+        alpn = "h2"
+        path = "/api"
+        dest = "8080"
+        proxy_protocol = 2
+
+        [[inbounds.fallbacks]]
+        dest = "site"
+        "#
+            ),
+            1,
+        );
+        let config = Config::parse(&content).unwrap();
+        let fallbacks = &config.inbounds[0].fallbacks;
+        assert_eq!(fallbacks.len(), 2);
+        assert_eq!(fallbacks[0].alpn.as_deref(), Some("h2"));
+        assert_eq!(fallbacks[0].path.as_deref(), Some("/api"));
+        assert_eq!(
+            fallbacks[0].dest,
+            FallbackDest::Tcp("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(fallbacks[1].dest, FallbackDest::Site);
+        assert_eq!(fallbacks[0].proxy_protocol, Some(ProxyVersion::V2));
+        assert_eq!(fallbacks[1].proxy_protocol, None);
+        let bad_version = content.replace("proxy_protocol = 2", "proxy_protocol = 3");
+        assert!(Config::parse(&bad_version).is_err());
+        let to_site = content.replace("dest = \"site\"\n", "dest = \"site\"\nproxy_protocol = 1\n");
+        assert!(Config::parse(&to_site).is_err());
+
+        let no_site = content.replace("[site]\n        listen = \"127.0.0.1:8888\"", "");
+        assert!(Config::parse(&no_site).is_err());
+        let relative = content.replace("\"/api\"", "\"api\"");
+        assert!(Config::parse(&relative).is_err());
+    }
+
+    #[test]
+    fn test_ws_path() {
+        let with_path = |path: &str| {
+            EXAMPLE.replace(
+                "transport = { type = \"ws\" }",
+                &format!(
+                    "transport = {{ type = \"ws\", path = \"{}\" }}\n        fallbacks = [{{ dest = \"site\" }}]",
+                    path
+                ),
+            )
+        };
+        let config = Config::parse(&with_path("/vless")).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::Ws(WsConfig { path: Some(path), .. }) if path == "/vless"
+        ));
+        assert_eq!(config.inbounds[1].fallbacks.len(), 1);
+        assert!(Config::parse(&with_path("vless")).is_err());
+
+        let no_listen = EXAMPLE.replace("listen = \"127.0.0.1:8888\"", "");
+        let config = Config::parse(&no_listen).unwrap();
+        assert_eq!(config.site.unwrap().listen, None);
+    }
+
+    #[test]
+    fn test_httpupgrade() {
+        let content = EXAMPLE.replace(
+            "transport = { type = \"ws\" }",
+            "transport = { type = \"httpupgrade\", path = \"/up\" }\n        routes = [{ reject = true }]",
+        );
+        let config = Config::parse(&content).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::HttpUpgrade(WsConfig { path: Some(path), .. }) if path == "/up"
+        ));
+        assert_eq!(config.inbounds[1].routes.len(), 1);
+    }
+
+    #[test]
+    fn test_split_http() {
+        let with_transport =
+            |transport: &str| EXAMPLE.replace("transport = { type = \"ws\" }", transport);
+        let config =
+            Config::parse(&with_transport("transport = { type = \"splithttp\" }")).unwrap();
+        let InboundTransport::SplitHttp(split_http) = &config.inbounds[1].transport else {
+            panic!("not splithttp");
+        };
+        assert_eq!(split_http.path, "/");
+        assert_eq!(split_http.max_post_bytes, 1_000_000);
+        let xhttp = "transport = { type = \"xhttp\", path = \"/x\", max_buffered_posts = 5 }";
+        let config = Config::parse(&with_transport(xhttp)).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::SplitHttp(SplitHttpConfig { path, max_buffered_posts: 5, .. }) if path == "/x"
+        ));
+        let no_buffer = "transport = { type = \"splithttp\", max_buffered_posts = 0 }";
+        assert!(Config::parse(&with_transport(no_buffer)).is_err());
+        let no_sessions = "transport = { type = \"splithttp\", max_pending_sessions = 0 }";
+        assert!(Config::parse(&with_transport(no_sessions)).is_err());
+    }
+
+    #[test]
+    fn test_quic() {
+        let quic = EXAMPLE.replace(
+            "transport = { type = \"ws\" }",
+            "transport = { type = \"quic\" }",
+        );
+        let config = Config::parse(&quic).unwrap();
+        assert!(matches!(
+            config.inbounds[1].transport,
+            InboundTransport::Quic
+        ));
+        // UDP and TCP on the same port.
+        let shared = quic.replace("127.0.0.1:34080", "127.0.0.1:34434");
+        assert!(Config::parse(&shared).is_ok());
+        let no_tls = quic.replace("tls = ", "# tls = ");
+        assert!(Config::parse(&no_tls).is_err());
+    }
+
+    #[test]
+    fn test_grpc() {
+        let with_transport =
+            |transport: &str| EXAMPLE.replace("transport = { type = \"ws\" }", transport);
+        let config = Config::parse(&with_transport("transport = { type = \"grpc\" }")).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::Grpc(GrpcConfig { service_name }) if service_name == "GunService"
+        ));
+        let custom = "transport = { type = \"grpc\", service_name = \"Tunnel\" }";
+        let config = Config::parse(&with_transport(custom)).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::Grpc(GrpcConfig { service_name }) if service_name == "Tunnel"
+        ));
+        let with_slash = "transport = { type = \"grpc\", service_name = \"a/b\" }";
+        assert!(Config::parse(&with_transport(with_slash)).is_err());
+    }
+
+    #[test]
+    fn test_h2() {
+        let with_transport = |transport: &str| {
+            EXAMPLE
+                .replace("transport = { type = \"ws\" }", transport)
+                .replace("alpn = [\"http/1.1\"]", "alpn = [\"h2\", \"http/1.1\"]")
+        };
+        let config = Config::parse(&with_transport("transport = { type = \"h2\" }")).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::H2(H2Config { host, path, method: None }) if host.is_empty() && path == "/"
+        ));
+        let custom = "transport = { type = \"h2\", host = [\"a.example\"], path = \"/h2\", method = \"POST\" }";
+        let config = Config::parse(&with_transport(custom)).unwrap();
+        assert!(matches!(
+            &config.inbounds[1].transport,
+            InboundTransport::H2(H2Config { host, method: Some(method), .. })
+                if host == &["a.example"] && method == "POST"
+        ));
+        let no_h2 = EXAMPLE.replace(
+            "transport = { type = \"ws\" }",
+            "transport = { type = \"h2\" }",
+        );
+        assert!(Config::parse(&no_h2).is_err());
+        let bad_path = "transport = { type = \"h2\", path = \"h2\" }";
+        assert!(Config::parse(&with_transport(bad_path)).is_err());
+    }
+
+    #[test]
+    fn test_ws_routes() {
+        let with_routes = |routes: &str| {
+            let tls = "alpn = [\"http/1.1\"] }";
+            EXAMPLE.replace(tls, &format!("{}\n{}", tls, routes))
+        };
+        let config = Config::parse(&with_routes(
+            r#"
+        [[inbounds.routes]]
+        path = "/private"
+        host = "example.com"
+        headers = { X-Token = "secret" }
+        users = ["test"]
+        outbound_proxy_protocol = { version = 2, to = ["10.0.0.0/8"] }
+        max_connections = 8
+
+        [[inbounds.routes]]
+        reject = true
+        "#,
+        ))
+        .unwrap();
+        let routes = &config.inbounds[1].routes;
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].host.as_deref(), Some("example.com"));
+        assert_eq!(routes[0].headers["X-Token"], "secret");
+        assert_eq!(routes[0].users, Some(vec![config.users[0].id]));
+        let relay = routes[0].outbound_proxy_protocol.as_ref().unwrap();
+        assert_eq!(relay.version, ProxyVersion::V2);
+        assert!(relay.to[0].contains(&"10.1.2.3".parse().unwrap()));
+        assert_eq!(routes[0].max_connections, Some(8));
+        assert!(!routes[0].reject);
+        assert!(routes[1].reject && routes[1].users.is_none());
+
+        for invalid in [
+            "[[inbounds.routes]]\nusers = [\"someone-else\"]",
+            "[[inbounds.routes]]\nreject = true\nusers = [\"test\"]",
+            "[[inbounds.routes]]\nreject = true\nmax_connections = 1",
+            "[[inbounds.routes]]\nmax_connections = 0",
+            "[[inbounds.routes]]\npath = \"private\"",
+        ] {
+            assert!(Config::parse(&with_routes(invalid)).is_err(), "{}", invalid);
+        }
+        let on_tcp = EXAMPLE.replace(
+            "listen = \"127.0.0.1:34434\"",
+            "listen = \"127.0.0.1:34434\"\nroutes = [{ reject = true }]",
+        );
+        assert!(Config::parse(&on_tcp).is_err());
+    }
+
+    #[test]
+    fn test_outbound_proxy_protocol() {
+        let with_relay = |relay: &str| {
+            EXAMPLE.replace(
+                "listen = \"127.0.0.1:34434\"",
+                &format!(
+                    "listen = \"127.0.0.1:34434\"\noutbound_proxy_protocol = {}",
+                    relay
+                ),
+            )
+        };
+        let config = Config::parse(&with_relay(
+            "{ version = 1, to = [\"127.0.0.1\", \"fd00::/8\"] }",
+        ))
+        .unwrap();
+        let relay = config.inbounds[0].outbound_proxy_protocol.as_ref().unwrap();
+        assert_eq!(relay.version, ProxyVersion::V1);
+        assert_eq!(relay.to.len(), 2);
+
+        for invalid in ["1", "{ version = 1 }", "{ version = 1, to = [] }"] {
+            assert!(Config::parse(&with_relay(invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let content = EXAMPLE.replace(
+            "transport = { type = \"ws\" }",
+            "transport = { type = \"ws\", trusted_proxies = [\"10.0.0.0/8\", \"::1\"], real_ip_header = \"X-Real-IP\" }",
+        );
+        let config = Config::parse(&content).unwrap();
+        let InboundTransport::Ws(ws) = &config.inbounds[1].transport else {
+            panic!("not a ws inbound");
+        };
+        assert_eq!(ws.real_ip_header, "X-Real-IP");
+        assert_eq!(ws.trusted_proxies.len(), 2);
+        let content = EXAMPLE.replace(
+            "transport = { type = \"ws\" }",
+            "transport = { type = \"ws\", trusted_proxies = [\"10.0.0.0/33\"] }",
+        );
+        assert!(Config::parse(&content).is_err());
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let tcp = "listen = \"127.0.0.1:34434\"";
+        let content = EXAMPLE.replace(
+            tcp,
+            &format!("{}\nproxy_protocol = {{ from = [\"10.0.0.0/8\"] }}", tcp),
+        );
+        let config = Config::parse(&content).unwrap();
+        let proxy_protocol = config.inbounds[0].proxy_protocol.as_ref().unwrap();
+        assert!(proxy_protocol.from[0].contains(&"10.1.1.1".parse().unwrap()));
+        assert!(config.inbounds[1].proxy_protocol.is_none());
+        let content = EXAMPLE.replace(tcp, &format!("{}\nproxy_protocol = {{ from = [] }}", tcp));
+        assert!(Config::parse(&content).is_err());
+    }
+
+    #[test]
+    fn test_ip_cidr() {
+        let cidr = |s: &str| IpCidr::try_from(s.to_string()).unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(cidr("10.0.0.0/8").contains(&ip("10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(&ip("11.0.0.1")));
+        assert!(cidr("10.0.0.0/8").contains(&ip("::ffff:10.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("1.2.3.4")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(cidr("2001:db8::/32").contains(&ip("2001:db8:1::1")));
+        assert!(cidr("::1").contains(&ip("::1")));
+        assert!(!cidr("127.0.0.1").contains(&ip("127.0.0.2")));
+        for invalid in ["10.0.0.0/33", "::/129", "example.com/8", "10.0.0.0/x"] {
+            assert!(
+                IpCidr::try_from(invalid.to_string()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_reject_invalid_user_id() {
+        let content = EXAMPLE.replace("id = \"test\"", "id = \"\"");
+        assert!(Config::parse(&content).is_err());
+    }
+
+    #[test]
+    fn test_reject_duplicate_tags() {
+        let content = EXAMPLE.replace("vless-ws", "vless-tcp");
+        assert!(Config::parse(&content).is_err());
+    }
+
+    #[test]
+    fn test_reject_duplicate_listen() {
+        let content = EXAMPLE.replace("34080", "34434");
+        assert!(Config::parse(&content).is_err());
+    }
+
+    #[test]
+    fn test_reject_no_users() {
+        let content = EXAMPLE.replace("[[users]]\n        id = \"test\"", "");
+        let content = format!("users = []\n{}", content);
+        assert!(Config::parse(&content)
+            .unwrap_err()
+            .downcast_ref::<serde_valid::Error<toml::de::Error>>()
+            .is_some_and(|e| e.is_validation_errors()));
+    }
+
+    #[test]
+    fn test_override_listen() {
+        let mut config = Config::parse(EXAMPLE).unwrap();
+        config
+            .override_listen("vless-ws", "0.0.0.0:443".parse().unwrap())
+            .unwrap();
+        assert_eq!(config.inbounds[1].listen, "0.0.0.0:443".parse().unwrap());
+        assert!(config
+            .override_listen("vless-ws", "127.0.0.1:34434".parse().unwrap())
+            .is_err());
+        assert!(config
+            .override_listen("missing", "0.0.0.0:443".parse().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_unix_listen() {
+        let unix = EXAMPLE.replace(
+            "listen = \"127.0.0.1:34080\"",
+            "listen = \"unix:/run/rocks/vless.sock\"\n        listen_mode = 0o660",
+        );
+        let config = Config::parse(&unix).unwrap();
+        assert_eq!(
+            config.inbounds[1].listen,
+            ListenAddr::Unix(PathBuf::from("/run/rocks/vless.sock"))
+        );
+        assert_eq!(config.inbounds[1].listen_mode, Some(0o660));
+        assert_eq!(
+            config.inbounds[1].listen.to_string(),
+            "unix:/run/rocks/vless.sock"
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        // A mode is only for Unix sockets, and QUIC cannot use them.
+        let mode_on_tcp = EXAMPLE.replace(
+            "listen = \"127.0.0.1:34080\"",
+            "listen = \"127.0.0.1:34080\"\n        listen_mode = 0o660",
+        );
+        assert!(Config::parse(&mode_on_tcp).is_err());
+        let quic = unix.replace("type = \"ws\"", "type = \"quic\"");
+        assert!(Config::parse(&quic).is_err());
+    }
+
+    #[test]
+    fn test_reject_unknown_transport() {
+        let content = EXAMPLE.replace("type = \"ws\"", "type = \"carrier-pigeon\"");
+        assert!(Config::parse(&content).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+// Fallbacks hand connections that are not VLESS, such as browsers and active
+// probes, to an ordinary web server. Everything read so far is replayed to
+// the chosen destination, which then talks to the client directly.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Error};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tracing::info;
+
+use crate::{
+    proxy_protocol::send_proxy_header, site::serve_site_connection, tcp::proxy, FallbackConfig,
+    FallbackDest, ProxyVersion,
+};
+
+/// What is known about a connection when picking its fallback.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FallbackInfo {
+    pub(crate) sni: Option<String>,
+    pub(crate) alpn: Option<String>,
+    /// The address the client connected to.
+    pub(crate) local_addr: Option<SocketAddr>,
+}
+
+/// The fallbacks of an inbound.
+#[derive(Debug, Clone)]
+pub struct Fallbacks {
+    rules: Vec<FallbackConfig>,
+    site_root: Option<PathBuf>,
+}
+
+/// The path of an HTTP/1 request line at the start of `buffer`.
+fn http_path(buffer: &[u8]) -> Option<&str> {
+    let line = buffer.split(|&b| b == b'\r' || b == b'\n').next()?;
+    let mut parts = line.split(|&b| b == b' ');
+    let method = parts.next()?;
+    if method.is_empty() || !method.iter().all(u8::is_ascii_uppercase) {
+        return None;
+    }
+    let path = std::str::from_utf8(parts.next()?).ok()?;
+    path.starts_with('/').then_some(path)
+}
+
+impl Fallbacks {
+    /// `site_root` is where the built-in site is served from, if there is one.
+    pub fn new(rules: Vec<FallbackConfig>, site_root: Option<PathBuf>) -> Self {
+        Self { rules, site_root }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The first rule matching the connection, given what was read from it.
+    fn select(&self, info: &FallbackInfo, initial: &[u8]) -> Option<&FallbackConfig> {
+        let path = http_path(initial);
+        self.rules.iter().find(|rule| {
+            let matches = |want: &Option<String>, got: Option<&str>| {
+                want.as_deref().is_none_or(|want| Some(want) == got)
+            };
+            matches(&rule.sni, info.sni.as_deref())
+                && matches(&rule.alpn, info.alpn.as_deref())
+                && rule
+                    .path
+                    .as_deref()
+                    .is_none_or(|want| path.is_some_and(|p| p.starts_with(want)))
+        })
+    }
+
+    /// Hand `connection` from `client` over to its fallback, replaying
+    /// `initial` first.
+    pub(crate) async fn serve(
+        &self,
+        connection: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        initial: &[u8],
+        info: &FallbackInfo,
+        client: SocketAddr,
+    ) -> Result<(), Error> {
+        let rule = self
+            .select(info, initial)
+            .ok_or_else(|| anyhow!("No fallback matches {:?}", info))?;
+        info!("fallback to {:?}", rule.dest);
+        // Where the client thought it was going, or failing that, anywhere.
+        let local_addr = info
+            .local_addr
+            .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+        let relay = rule.proxy_protocol.map(|v| (v, client, local_addr));
+        match &rule.dest {
+            FallbackDest::Site => {
+                let root = self
+                    .site_root
+                    .clone()
+                    .ok_or_else(|| anyhow!("No site to fall back to"))?;
+                serve_site_connection(Prefixed::new(initial.to_vec(), connection), root).await
+            }
+            FallbackDest::Tcp(addr) => {
+                let target = TcpStream::connect(addr.as_str()).await?;
+                splice(connection, initial, target, relay).await
+            }
+            #[cfg(unix)]
+            FallbackDest::Unix(path) => {
+                let target = tokio::net::UnixStream::connect(path).await?;
+                splice(connection, initial, target, relay).await
+            }
+            #[cfg(not(unix))]
+            FallbackDest::Unix(path) => Err(anyhow!(
+                "Unix sockets are not supported here: {}",
+                path.display()
+            )),
+        }
+    }
+}
+
+async fn splice(
+    connection: impl AsyncRead + AsyncWrite,
+    initial: &[u8],
+    target: impl AsyncRead + AsyncWrite,
+    relay: Option<(ProxyVersion, SocketAddr, SocketAddr)>,
+) -> Result<(), Error> {
+    let (in_rd, in_wr) = tokio::io::split(connection);
+    let (out_rd, mut out_wr) = tokio::io::split(target);
+    if let Some((version, client, local_addr)) = relay {
+        send_proxy_header(&mut out_wr, version, client, local_addr).await?;
+    }
+    out_wr.write_all(initial).await?;
+    proxy(in_rd, in_wr, out_rd, out_wr).await
+}
+
+/// A stream that reads `prefix` before anything from `inner`.
+#[pin_project::pin_project]
+pub(crate) struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    #[pin]
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub(crate) fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        if *this.pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - *this.pos);
+            buf.put_slice(&this.prefix[*this.pos..*this.pos + n]);
+            *this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proxy_protocol::resolve_peer, IpCidr, ProxyProtocolConfig};
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    fn rule(
+        sni: Option<&str>,
+        alpn: Option<&str>,
+        path: Option<&str>,
+        dest: &str,
+    ) -> FallbackConfig {
+        FallbackConfig {
+            sni: sni.map(String::from),
+            alpn: alpn.map(String::from),
+            path: path.map(String::from),
+            dest: FallbackDest::try_from(dest.to_string()).unwrap(),
+            proxy_protocol: None,
+        }
+    }
+
+    #[test]
+    fn test_http_path() {
+        assert_eq!(
+            http_path(b"GET /index.html HTTP/1.1\r\n"),
+            Some("/index.html")
+        );
+        assert_eq!(http_path(b"POST /api"), Some("/api"));
+        assert_eq!(http_path(b"\x00\x01GET / HTTP/1.1"), None);
+        assert_eq!(http_path(b"GET example.com HTTP/1.1"), None);
+    }
+
+    #[test]
+    fn test_select() {
+        let fallbacks = Fallbacks::new(
+            vec![
+                rule(Some("a.example"), None, None, "1001"),
+                rule(None, Some("h2"), None, "1002"),
+                rule(None, None, Some("/api"), "1003"),
+                rule(None, None, None, "site"),
+            ],
+            None,
+        );
+        let dest = |sni: Option<&str>, alpn: Option<&str>, initial: &[u8]| {
+            let info = FallbackInfo {
+                sni: sni.map(String::from),
+                alpn: alpn.map(String::from),
+                local_addr: None,
+            };
+            fallbacks.select(&info, initial).map(|r| r.dest.clone())
+        };
+        let tcp = |port: u16| Some(FallbackDest::Tcp(format!("127.0.0.1:{}", port)));
+        assert_eq!(dest(Some("a.example"), Some("h2"), b""), tcp(1001));
+        assert_eq!(dest(Some("b.example"), Some("h2"), b""), tcp(1002));
+        assert_eq!(dest(None, None, b"GET /api/v1 HTTP/1.1\r\n"), tcp(1003));
+        assert_eq!(
+            dest(None, None, b"GET / HTTP/1.1\r\n"),
+            Some(FallbackDest::Site)
+        );
+    }
+
+    fn client_addr() -> SocketAddr {
+        "192.0.2.1:5000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_dest() {
+        let parse = |s: &str| FallbackDest::try_from(s.to_string());
+        assert_eq!(parse("site"), Ok(FallbackDest::Site));
+        assert_eq!(parse("80"), Ok(FallbackDest::Tcp("127.0.0.1:80".into())));
+        assert_eq!(
+            parse("example.com:8080"),
+            Ok(FallbackDest::Tcp("example.com:8080".into()))
+        );
+        assert_eq!(
+            parse("/run/web.sock"),
+            Ok(FallbackDest::Unix(PathBuf::from("/run/web.sock")))
+        );
+        assert!(parse("nowhere").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 9];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"GET / 1.0");
+            stream.write_all(b"ok").await.unwrap();
+        });
+
+        let fallbacks = Fallbacks::new(vec![rule(None, None, None, &addr.to_string())], None);
+        let (mut client, server) = tokio::io::duplex(1024);
+        let serve = tokio::spawn(async move {
+            fallbacks
+                .serve(server, b"GET /", &FallbackInfo::default(), client_addr())
+                .await
+        });
+        client.write_all(b" 1.0").await.unwrap();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"ok");
+        serve.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_proxy_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            let config = ProxyProtocolConfig {
+                from: vec![IpCidr::try_from("127.0.0.1".to_string()).unwrap()],
+            };
+            let client = resolve_peer(&mut stream, peer, Some(&config))
+                .await
+                .unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"GET /");
+            client
+        });
+
+        let fallbacks = Fallbacks::new(
+            vec![FallbackConfig {
+                proxy_protocol: Some(ProxyVersion::V1),
+                ..rule(None, None, None, &addr.to_string())
+            }],
+            None,
+        );
+        let info = FallbackInfo {
+            local_addr: Some("192.0.2.2:443".parse().unwrap()),
+            ..FallbackInfo::default()
+        };
+        let (_client, server_side) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            fallbacks
+                .serve(server_side, b"GET /", &info, client_addr())
+                .await
+        });
+        assert_eq!(server.await.unwrap(), client_addr());
+    }
+}
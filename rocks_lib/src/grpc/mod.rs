@@ -0,0 +1,395 @@
+// gRPC transport, the "gun" protocol of Xray and v2ray. The client calls the
+// streaming method `Tun` of a service, `GunService` unless configured
+// otherwise, over HTTP/2. Each gRPC message carries a chunk of the proxied
+// byte stream in a protobuf `Hunk { bytes data = 1; }`; with `TunMulti` a
+// `MultiHunk { repeated bytes data = 1; }` may carry several chunks at once.
+// A single chunk encodes the same either way, so replies are the same too.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::{anyhow, Error};
+use futures::{Sink, Stream};
+use h2::RecvStream;
+use http::{header::HeaderValue, HeaderMap, Method, Request};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    http2::{serve_bodies, BodySink, BodyStream},
+    BufferFormer, BufferParseResult, BufferParser, InsufficientBuffer,
+};
+
+/// The compressed flag and length in front of every gRPC message.
+const MESSAGE_HEADER_LEN: usize = 5;
+/// Field 1, length-delimited: the `data` of a hunk.
+const DATA_TAG: u8 = 0x0a;
+/// The longest gRPC message taken, so a length prefix cannot make a stream
+/// buffer without bound.
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum GunParseError {
+    #[error("Compressed gRPC messages are not supported")]
+    Compressed,
+    #[error("Invalid hunk")]
+    InvalidHunk,
+    #[error("gRPC message of {0} bytes is too long")]
+    TooLong(usize),
+}
+
+/// A gRPC message of the gun protocol, with the chunks of data it carries.
+#[derive(Debug, PartialEq)]
+pub struct GunMessage<'a> {
+    pub hunks: Vec<&'a [u8]>,
+}
+
+fn read_varint(buffer: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, &b) in buffer.iter().enumerate().take(10) {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn varint_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+impl<'a> BufferParser<'a> for GunMessage<'a> {
+    type Error = GunParseError;
+    type ParseOptions = ();
+
+    fn parse_with_options<'b>(
+        buffer: &'b [u8],
+        _: Self::ParseOptions,
+    ) -> BufferParseResult<Self, Self::Error>
+    where
+        'b: 'a,
+    {
+        if buffer.len() < MESSAGE_HEADER_LEN {
+            return BufferParseResult::Incomplete {
+                needed: MESSAGE_HEADER_LEN - buffer.len(),
+            };
+        }
+        if buffer[0] != 0 {
+            return BufferParseResult::Error(GunParseError::Compressed);
+        }
+        let len = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return BufferParseResult::Error(GunParseError::TooLong(len));
+        }
+        let size = MESSAGE_HEADER_LEN + len;
+        if buffer.len() < size {
+            return BufferParseResult::Incomplete {
+                needed: size - buffer.len(),
+            };
+        }
+        let mut body = &buffer[MESSAGE_HEADER_LEN..size];
+        let mut hunks = vec![];
+        while let Some((&tag, rest)) = body.split_first() {
+            let Some((len, len_size)) = read_varint(rest).filter(|_| tag == DATA_TAG) else {
+                return BufferParseResult::Error(GunParseError::InvalidHunk);
+            };
+            let rest = &rest[len_size..];
+            let Some(hunk) = usize::try_from(len).ok().and_then(|len| rest.get(..len)) else {
+                return BufferParseResult::Error(GunParseError::InvalidHunk);
+            };
+            hunks.push(hunk);
+            body = &rest[hunk.len()..];
+        }
+        BufferParseResult::Parsed {
+            value: Self { hunks },
+            size,
+        }
+    }
+}
+
+impl<'a> BufferFormer for GunMessage<'a> {
+    type Error = InsufficientBuffer;
+    type FormingOptions = ();
+
+    fn size_with_option(&self, _: &Self::FormingOptions) -> usize {
+        MESSAGE_HEADER_LEN + self.body_size()
+    }
+
+    fn form_with_option<'b>(
+        &'b self,
+        buffer: &'b mut [u8],
+        _: &Self::FormingOptions,
+    ) -> Result<usize, Self::Error> {
+        let size = self.size();
+        let body_size = u32::try_from(self.body_size()).map_err(|_| InsufficientBuffer)?;
+        if buffer.len() < size {
+            return Err(InsufficientBuffer);
+        }
+        buffer[0] = 0;
+        buffer[1..MESSAGE_HEADER_LEN].copy_from_slice(&body_size.to_be_bytes());
+        let mut offset = MESSAGE_HEADER_LEN;
+        for hunk in &self.hunks {
+            buffer[offset] = DATA_TAG;
+            offset += 1;
+            let mut len = hunk.len() as u64;
+            while len >= 0x80 {
+                buffer[offset] = (len as u8) | 0x80;
+                len >>= 7;
+                offset += 1;
+            }
+            buffer[offset] = len as u8;
+            offset += 1;
+            buffer[offset..offset + hunk.len()].copy_from_slice(hunk);
+            offset += hunk.len();
+        }
+        Ok(offset)
+    }
+}
+
+impl GunMessage<'_> {
+    fn body_size(&self) -> usize {
+        self.hunks
+            .iter()
+            .map(|hunk| 1 + varint_len(hunk.len() as u64) + hunk.len())
+            .sum()
+    }
+}
+
+/// The data a client sends on a `Tun` call, one message at a time.
+pub(crate) struct GunStream {
+    body: BodyStream,
+    buffer: Vec<u8>,
+}
+
+impl GunStream {
+    fn new(body: BodyStream) -> Self {
+        Self {
+            body,
+            buffer: vec![],
+        }
+    }
+}
+
+impl Stream for GunStream {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let (data, size) = match GunMessage::parse(&this.buffer) {
+                BufferParseResult::Parsed { value, size } => (value.hunks.concat(), size),
+                BufferParseResult::Incomplete { .. } => (vec![], 0),
+                BufferParseResult::Error(e) => return Poll::Ready(Some(Err(e.into()))),
+            };
+            if size > 0 {
+                this.buffer.drain(..size);
+                return Poll::Ready(Some(Ok(data)));
+            }
+            match ready!(Pin::new(&mut this.body).poll_next(cx)) {
+                Some(Ok(chunk)) => this.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None if this.buffer.is_empty() => return Poll::Ready(None),
+                None => return Poll::Ready(Some(Err(anyhow!("Truncated gRPC message")))),
+            }
+        }
+    }
+}
+
+/// Sends every message as a hunk of the reply to a `Tun` call, and the
+/// status of the call when closed.
+pub(crate) struct GunSink {
+    body: BodySink,
+}
+
+impl GunSink {
+    fn new(body: BodySink) -> Self {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        Self {
+            body: body.with_trailers(trailers),
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for GunSink {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        let message = GunMessage { hunks: vec![&item] };
+        let mut buffer = vec![0; message.size()];
+        message
+            .form(&mut buffer)
+            .map_err(|_| anyhow!("Message too large for gRPC"))?;
+        Pin::new(&mut self.get_mut().body).start_send(buffer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_close(cx)
+    }
+}
+
+/// Serve the gun calls of `service_name` on an HTTP/2 connection, giving the
+/// stream of each to `handle`. Other requests are answered with a 404.
+pub(crate) async fn serve_gun<F, Fut>(
+    connection: impl AsyncRead + AsyncWrite + Unpin,
+    service_name: &str,
+    handle: F,
+) -> Result<(), Error>
+where
+    F: Fn(GunStream, GunSink) -> Fut,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let tun = format!("/{}/Tun", service_name);
+    let tun_multi = format!("/{}/TunMulti", service_name);
+    let accepts = |request: &Request<RecvStream>| {
+        let path = request.uri().path();
+        request.method() == Method::POST && (path == tun || path == tun_multi)
+    };
+    serve_bodies(connection, accepts, "application/grpc", |stream, sink| {
+        handle(GunStream::new(stream), GunSink::new(sink))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use http::{header::CONTENT_TYPE, StatusCode};
+
+    fn message(hunks: &[&[u8]]) -> Vec<u8> {
+        let message = GunMessage {
+            hunks: hunks.to_vec(),
+        };
+        let mut buffer = vec![0; message.size()];
+        assert_eq!(message.form(&mut buffer), Ok(buffer.len()));
+        buffer
+    }
+
+    #[test]
+    fn test_parse_hunk() {
+        let buffer = [0, 0, 0, 0, 5, 0x0a, 3, 1, 2, 3, 0xff];
+        let BufferParseResult::Parsed { value, size } = GunMessage::parse(&buffer) else {
+            panic!("not parsed");
+        };
+        assert_eq!(value.hunks, [&[1, 2, 3][..]]);
+        assert_eq!(size, 10);
+        assert!(matches!(
+            GunMessage::parse(&buffer[..7]),
+            BufferParseResult::Incomplete { needed: 3 }
+        ));
+        assert!(matches!(
+            GunMessage::parse(&buffer[..2]),
+            BufferParseResult::Incomplete { needed: 3 }
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let error = |buffer: &[u8]| match GunMessage::parse(buffer) {
+            BufferParseResult::Error(e) => Some(e),
+            _ => None,
+        };
+        assert_eq!(error(&[1, 0, 0, 0, 0]), Some(GunParseError::Compressed));
+        // Wrong field, and a hunk longer than its message.
+        assert_eq!(
+            error(&[0, 0, 0, 0, 2, 0x12, 0]),
+            Some(GunParseError::InvalidHunk)
+        );
+        assert_eq!(
+            error(&[0, 0, 0, 0, 3, 0x0a, 4, 1]),
+            Some(GunParseError::InvalidHunk)
+        );
+        // Refused from the header alone, before the message is buffered.
+        let len = (MAX_MESSAGE_LEN as u32 + 1).to_be_bytes();
+        assert_eq!(
+            error(&[0, len[0], len[1], len[2], len[3]]),
+            Some(GunParseError::TooLong(MAX_MESSAGE_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn test_form_roundtrip() {
+        let long = vec![7; 300];
+        let buffer = message(&[b"abc", &long, b""]);
+        // A 300 byte hunk needs a two byte length.
+        assert_eq!(buffer.len(), 5 + 5 + 303 + 2);
+        let BufferParseResult::Parsed { value, size } = GunMessage::parse(&buffer) else {
+            panic!("not parsed");
+        };
+        assert_eq!(value.hunks, [&b"abc"[..], &long, b""]);
+        assert_eq!(size, buffer.len());
+    }
+
+    #[tokio::test]
+    async fn test_serve_gun() {
+        let (client, server) = tokio::io::duplex(65536);
+        tokio::spawn(serve_gun(
+            server,
+            "GunService",
+            |mut stream, mut sink| async move {
+                while let Some(data) = stream.next().await {
+                    sink.send(data?).await?;
+                }
+                sink.close().await
+            },
+        ));
+
+        let (h2, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let mut h2 = h2.ready().await.unwrap();
+        let call = |path: &str| {
+            Request::post(format!("http://example.com{}", path))
+                .header(CONTENT_TYPE, "application/grpc")
+                .body(())
+                .unwrap()
+        };
+
+        let (response, _) = h2.send_request(call("/Other/Tun"), true).unwrap();
+        assert_eq!(response.await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        let mut h2 = h2.ready().await.unwrap();
+        let (response, mut send) = h2
+            .send_request(call("/GunService/TunMulti"), false)
+            .unwrap();
+        send.send_data(message(&[b"hel", b"lo"]).into(), false)
+            .unwrap();
+        let request = message(&[b" world"]);
+        send.send_data(Bytes::copy_from_slice(&request[..4]), false)
+            .unwrap();
+        send.send_data(Bytes::copy_from_slice(&request[4..]), true)
+            .unwrap();
+
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut received = vec![];
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(
+            received,
+            [message(&[b"hello"]), message(&[b" world"])].concat()
+        );
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+}
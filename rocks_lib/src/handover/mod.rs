@@ -0,0 +1,413 @@
+// Zero-downtime upgrades. The running server starts its binary anew, which
+// an upgrade has replaced, and hands the new server its listening sockets
+// over a Unix socket, each named by what it is for. Once the new server has
+// taken them and is serving, the old one stops accepting and lets the
+// sessions it has finish before exiting.
+//
+// The names go as lines of text, `inbound TAG` or `site`, along with the
+// sockets themselves as SCM_RIGHTS. The new server answers with a line of
+// its own when it is serving. The socket has a path anyone may connect to,
+// so the listeners only go to a peer that is the new server itself.
+//
+// The new server is a child of the old one. Under systemd, the old server
+// then reports it as the main process of the service, so that the service
+// is not stopped, new server included, when the old one exits.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::{IoSlice, IoSliceMut, Read, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            net::{UnixDatagram, UnixStream},
+        },
+    },
+    path::PathBuf,
+    process::{Child, Command},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Error};
+use nix::{
+    cmsg_space,
+    sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+};
+use tokio::{net::UnixListener, time::Instant};
+use tracing::info;
+
+use crate::{listener::open_connections, Listener};
+
+/// Set for a server started by an upgrade, to the socket it connects to for
+/// the listeners of the server it replaces.
+const HANDOVER_ENV: &str = "ROCKS_HANDOVER";
+
+/// Set by systemd to the socket service notifications go to.
+const NOTIFY_ENV: &str = "NOTIFY_SOCKET";
+
+/// How long the new server has to start serving.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most listeners one handover carries.
+const MAX_LISTENERS: usize = 64;
+
+/// What a listener handed over is for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenerRole {
+    /// The listener of the inbound with this tag.
+    Inbound(String),
+    /// The listener of the site.
+    Site,
+}
+
+impl ListenerRole {
+    fn encode(&self) -> String {
+        match self {
+            Self::Inbound(tag) => format!("inbound {}", tag),
+            Self::Site => "site".to_string(),
+        }
+    }
+
+    fn decode(line: &str) -> Result<Self, Error> {
+        match line.split_once(' ') {
+            Some(("inbound", tag)) => Ok(Self::Inbound(tag.to_string())),
+            None if line == "site" => Ok(Self::Site),
+            _ => Err(anyhow!("unknown listener `{}` handed over", line)),
+        }
+    }
+}
+
+/// Listeners handed over, by what they are for.
+pub type HandedOver = HashMap<ListenerRole, Listener>;
+
+/// Send `listeners` over `stream`.
+fn send_listeners(stream: &UnixStream, listeners: &[(ListenerRole, RawFd)]) -> Result<(), Error> {
+    if listeners.len() > MAX_LISTENERS {
+        return Err(anyhow!(
+            "cannot hand over more than {} listeners",
+            MAX_LISTENERS
+        ));
+    }
+    let names: String = listeners
+        .iter()
+        .map(|(role, _)| role.encode() + "\n")
+        .collect();
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| *fd).collect();
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(names.as_bytes())],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    // The sockets went with the first byte; the rest of the names follow.
+    (&*stream).write_all(&names.as_bytes()[sent..])?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Receive the listeners sent over `stream` by `send_listeners`.
+fn receive_listeners(stream: &UnixStream) -> Result<HandedOver, Error> {
+    let mut buffer = vec![0; 4096];
+    let mut cmsg_buffer = cmsg_space!([RawFd; MAX_LISTENERS]);
+    let mut iov = [IoSliceMut::new(&mut buffer)];
+    let message = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    let mut fds = vec![];
+    for cmsg in message.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            // SAFETY: the descriptors were just received, nothing else in
+            // this process has them.
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    let received = message.bytes;
+    buffer.truncate(received);
+    (&*stream).read_to_end(&mut buffer)?;
+    let names = String::from_utf8(buffer)?;
+    let roles = names
+        .lines()
+        .map(ListenerRole::decode)
+        .collect::<Result<Vec<_>, _>>()?;
+    if roles.len() != fds.len() {
+        return Err(anyhow!(
+            "{} listeners named but {} handed over",
+            roles.len(),
+            fds.len()
+        ));
+    }
+    roles
+        .into_iter()
+        .zip(fds)
+        .map(|(role, fd)| Ok((role, Listener::from_fd(fd)?)))
+        .collect()
+}
+
+/// The server an upgrade replaces, waiting for this one to start serving.
+pub struct Handover {
+    stream: UnixStream,
+}
+
+impl Handover {
+    /// Take the listeners of the server being replaced, if this one was
+    /// started by an upgrade.
+    pub fn receive() -> Result<Option<(Self, HandedOver)>, Error> {
+        let Some(path) = std::env::var_os(HANDOVER_ENV) else {
+            return Ok(None);
+        };
+        let stream = UnixStream::connect(&path)
+            .with_context(|| format!("connecting to {}", PathBuf::from(path).display()))?;
+        let listeners = receive_listeners(&stream)?;
+        info!("took over {} listener(s)", listeners.len());
+        Ok(Some((Self { stream }, listeners)))
+    }
+
+    /// Tell the server being replaced that this one is serving.
+    pub fn ready(self) -> Result<(), Error> {
+        (&self.stream).write_all(b"ready\n")?;
+        Ok(())
+    }
+}
+
+/// Hand `listeners` over to the new server, process `child`, once it
+/// connects to `socket`, and wait for it to start serving. Whoever else
+/// connects is turned away.
+async fn hand_over(
+    socket: &UnixListener,
+    child: u32,
+    listeners: &[(ListenerRole, RawFd)],
+) -> Result<(), Error> {
+    let stream = loop {
+        let (stream, _) = socket.accept().await?;
+        let pid = stream.peer_cred()?.pid();
+        if pid.and_then(|pid| u32::try_from(pid).ok()) == Some(child) {
+            break stream;
+        }
+        info!(
+            "process {:?} connected for the handover, turning it away",
+            pid
+        );
+    };
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+    let listeners = listeners.to_vec();
+    tokio::task::spawn_blocking(move || {
+        send_listeners(&stream, &listeners)?;
+        let mut answer = String::new();
+        (&stream).read_to_string(&mut answer)?;
+        match answer.as_str() {
+            "ready\n" => Ok(()),
+            _ => Err(anyhow!("the new server failed to start")),
+        }
+    })
+    .await?
+}
+
+/// Tell the service manager listening on `socket`, as named by
+/// `NOTIFY_SOCKET`, that process `pid` is the main process of the service.
+fn notify_main_pid(socket: &OsStr, pid: u32) -> Result<(), Error> {
+    let message = format!("MAINPID={}\n", pid);
+    let sender = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            sender.send_to_addr(message.as_bytes(), &SocketAddr::from_abstract_name(name)?)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(anyhow!("abstract notification sockets need Linux")),
+        None => sender.send_to(message.as_bytes(), socket)?,
+    };
+    Ok(())
+}
+
+/// Fail when `child` exits, which it should not while taking over.
+async fn exited(child: &mut Child) -> Result<(), Error> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!("the new server exited with {}", status));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Start the binary this server was started as, with the same arguments,
+/// and hand it `listeners`. Returns once the new server is serving, after
+/// which this one should stop accepting; on failure the new server is
+/// stopped and this one goes on as before.
+pub async fn upgrade(listeners: &[(ListenerRole, RawFd)]) -> Result<(), Error> {
+    let path = std::env::temp_dir().join(format!("rocks-handover-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixListener::bind(&path)?;
+    let mut args = std::env::args_os();
+    let program = args.next().unwrap_or_else(|| OsString::from("rocks_svr"));
+    let mut child = Command::new(program)
+        .args(args)
+        .env(HANDOVER_ENV, &path)
+        .spawn()
+        .context("starting the new server")?;
+    let id = child.id();
+    info!("started new server {}", id);
+    let result = tokio::time::timeout(HANDOVER_TIMEOUT, async {
+        tokio::select! {
+            r = hand_over(&socket, id, listeners) => r,
+            r = exited(&mut child) => r,
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err(anyhow!("the new server took too long to start")));
+    let _ = std::fs::remove_file(&path);
+    if result.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    } else if let Some(socket) = std::env::var_os(NOTIFY_ENV) {
+        // The new server is serving already; it takes over regardless.
+        notify_main_pid(&socket, id)
+            .unwrap_or_else(|e| info!("could not tell systemd about the new server: {:?}", e));
+    }
+    result
+}
+
+/// Wait for the connections still open to close, for at most `timeout`.
+/// Returns whether they all did.
+pub async fn drain(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = open_connections();
+        if open == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            info!("{} connection(s) still open at the deadline", open);
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListenAddr;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    #[test]
+    fn test_roles() {
+        for role in [
+            ListenerRole::Inbound("vless ws".to_string()),
+            ListenerRole::Site,
+        ] {
+            assert_eq!(ListenerRole::decode(&role.encode()).unwrap(), role);
+        }
+        assert!(ListenerRole::decode("outbound x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_receive_listeners() {
+        let tcp = Listener::bind(&"127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let ListenAddr::Inet(addr) = tcp.local_addr().unwrap() else {
+            panic!("not on TCP");
+        };
+        let dir = std::env::temp_dir().join(format!("rocks-handover-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let unix = Listener::bind(&ListenAddr::Unix(dir.join("site.sock")), None)
+            .await
+            .unwrap();
+
+        let (old, new) = UnixStream::pair().unwrap();
+        let tag = "t".repeat(5000);
+        let listeners = [
+            (ListenerRole::Inbound(tag.clone()), tcp.as_raw_fd()),
+            (ListenerRole::Site, unix.as_raw_fd()),
+        ];
+        send_listeners(&old, &listeners).unwrap();
+        let mut received = receive_listeners(&new).unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(
+            received[&ListenerRole::Site].local_addr().unwrap(),
+            unix.local_addr().unwrap()
+        );
+
+        // The old listener can go, the new one takes the connections.
+        drop(tcp);
+        let listener = received.remove(&ListenerRole::Inbound(tag)).unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hand_over_to_child_only() {
+        let path = std::env::temp_dir().join(format!(
+            "rocks-handover-child-test-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixListener::bind(&path).unwrap();
+        let tcp = Listener::bind(&"127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let listeners = [(ListenerRole::Site, tcp.as_raw_fd())];
+
+        // Another process connecting first gets nothing.
+        let mut stranger = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let handing_over = hand_over(&socket, std::process::id() + 1, &listeners);
+        tokio::pin!(handing_over);
+        let mut answer = vec![];
+        tokio::select! {
+            r = &mut handing_over => panic!("handed over to a stranger: {:?}", r),
+            r = stranger.read_to_end(&mut answer) => assert_eq!(r.unwrap(), 0),
+        }
+
+        let connect = path.clone();
+        let new = tokio::task::spawn_blocking(move || {
+            let stream = UnixStream::connect(connect).unwrap();
+            let listeners = receive_listeners(&stream).unwrap();
+            Handover { stream }.ready().unwrap();
+            listeners.len()
+        });
+        hand_over(&socket, std::process::id(), &listeners)
+            .await
+            .unwrap();
+        assert_eq!(new.await.unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_notify_main_pid() {
+        let path =
+            std::env::temp_dir().join(format!("rocks-notify-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        notify_main_pid(path.as_os_str(), 1234).unwrap();
+        let mut message = [0; 64];
+        let n = systemd.recv(&mut message).unwrap();
+        assert_eq!(&message[..n], b"MAINPID=1234\n");
+        std::fs::remove_file(&path).unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let name = format!("rocks-notify-test-{}", std::process::id());
+            let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+            let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+            notify_main_pid(OsStr::new(&format!("@{}", name)), 5678).unwrap();
+            let n = systemd.recv(&mut message).unwrap();
+            assert_eq!(&message[..n], b"MAINPID=5678\n");
+        }
+    }
+}
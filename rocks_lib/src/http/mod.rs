@@ -0,0 +1,192 @@
+// Just enough HTTP/1 to decide what to do with a connection before handing it
+// to whoever serves it: read the request head, look at its path and headers,
+// and answer with a bare status when nobody else will.
+
+use anyhow::{anyhow, Error};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The most a request head may take before the connection is given up on.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+const MAX_HEADERS: usize = 64;
+
+/// Read from `stream` until a whole request head is buffered. Whatever
+/// follows the head in the last read is returned along with it.
+pub(crate) async fn read_request_head(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![0; MAX_REQUEST_HEAD];
+    let mut offset = 0;
+    loop {
+        if offset == buffer.len() {
+            return Err(anyhow!("Request head longer than {} bytes", buffer.len()));
+        }
+        let n = stream.read(&mut buffer[offset..]).await?;
+        if n == 0 {
+            return Err(anyhow!("EOF before the end of the request head"));
+        }
+        let searched = offset.saturating_sub(3);
+        offset += n;
+        if buffer[searched..offset]
+            .windows(4)
+            .any(|w| w == b"\r\n\r\n")
+        {
+            buffer.truncate(offset);
+            return Ok(buffer);
+        }
+    }
+}
+
+/// The length of the request head at the start of `buffer`, up to and
+/// including the empty line ending it.
+pub(crate) fn head_len(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// The parts of a request head routing decisions are made on.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    /// The request target, query included.
+    pub(crate) target: String,
+    pub(crate) headers: Vec<(String, Vec<u8>)>,
+}
+
+impl RequestHead {
+    pub(crate) fn parse(buffer: &[u8]) -> Result<Self, Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(buffer)? {
+            httparse::Status::Complete(_) => {}
+            httparse::Status::Partial => return Err(anyhow!("Incomplete request head")),
+        }
+        Ok(Self {
+            method: request.method.unwrap_or_default().to_string(),
+            target: request.path.unwrap_or_default().to_string(),
+            headers: request
+                .headers
+                .iter()
+                .map(|h| (h.name.to_string(), h.value.to_vec()))
+                .collect(),
+        })
+    }
+
+    /// The head of a request already parsed by hyper.
+    pub(crate) fn from_request<B>(request: &hyper::Request<B>) -> Self {
+        Self {
+            method: request.method().to_string(),
+            target: request.uri().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    /// The request path, without the query.
+    pub(crate) fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// The first header named `name`, compared case-insensitively.
+    pub(crate) fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Whether the client asks to switch to WebSocket.
+    pub(crate) fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|v| v.eq_ignore_ascii_case(b"websocket"))
+    }
+}
+
+/// Answer with an empty response of `status`, e.g. `"404 Not Found"`, and
+/// close the connection.
+pub(crate) async fn respond_status(
+    mut stream: impl AsyncWrite + Unpin,
+    status: &str,
+) -> Result<(), Error> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Accept a WebSocket upgrade without WebSocket framing to follow, as
+/// HTTPUpgrade clients expect, echoing `protocol` if one was offered.
+pub(crate) async fn respond_upgrade(
+    stream: &mut (impl AsyncWrite + Unpin),
+    protocol: Option<&[u8]>,
+) -> Result<(), Error> {
+    let mut response =
+        b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n"
+            .to_vec();
+    if let Some(protocol) = protocol {
+        response.extend_from_slice(b"Sec-WebSocket-Protocol: ");
+        response.extend_from_slice(protocol);
+        response.extend_from_slice(b"\r\n");
+    }
+    response.extend_from_slice(b"\r\n");
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request_head() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for part in [&b"GET /a HTTP/1.1\r\nHost: x\r"[..], b"\n\r", b"\nbody"] {
+                client.write_all(part).await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        });
+        let head = read_request_head(&mut server).await.unwrap();
+        assert!(head.starts_with(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(head_len(&head), Some(28));
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        drop(client);
+        assert!(read_request_head(&mut server).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_request_head() {
+        let head = RequestHead::parse(
+            b"GET /vless?ed=2048 HTTP/1.1\r\nHost: example.com\r\nUpgrade: WebSocket\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.target, "/vless?ed=2048");
+        assert_eq!(head.path(), "/vless");
+        assert_eq!(head.header("HOST"), Some(&b"example.com"[..]));
+        assert!(head.is_websocket_upgrade());
+        assert!(RequestHead::parse(b"\x00\x01 junk\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_respond_upgrade() {
+        let mut response = vec![];
+        respond_upgrade(&mut response, Some(b"AAEC")).await.unwrap();
+        assert_eq!(
+            response,
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Protocol: AAEC\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// HTTP/2 for the transports carrying VLESS in request and response bodies,
+// gun and h2: telling HTTP/2 connections apart, accepting the requests meant
+// for a transport, and body streams that keep to HTTP/2 flow control.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::{anyhow, Error};
+use bytes::Bytes;
+use futures::{Sink, Stream};
+use h2::{RecvStream, SendStream};
+use http::{header::CONTENT_TYPE, HeaderMap, Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tracing::info;
+
+use crate::H2Config;
+
+/// What every HTTP/2 connection starts with.
+pub(crate) const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Read the start of `stream` for as long as it matches the HTTP/2 preface.
+/// Anything else returns as soon as it is told apart.
+pub(crate) async fn read_preface(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, Error> {
+    let mut read = vec![];
+    while read.len() < H2_PREFACE.len() && H2_PREFACE.starts_with(&read) {
+        let mut buf = [0; H2_PREFACE.len()];
+        let n = stream
+            .read(&mut buf[..H2_PREFACE.len() - read.len()])
+            .await?;
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+    Ok(read)
+}
+
+/// The body of a request, as it arrives.
+pub(crate) struct BodyStream {
+    recv: RecvStream,
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match ready!(this.recv.poll_data(cx)) {
+            Some(Ok(chunk)) => {
+                this.recv.flow_control().release_capacity(chunk.len())?;
+                Poll::Ready(Some(Ok(chunk.to_vec())))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// The body of a response, sent within the flow control window the client
+/// gives. Closing it ends the response, with `trailers` if set.
+pub(crate) struct BodySink {
+    send: SendStream<Bytes>,
+    pending: Bytes,
+    trailers: Option<HeaderMap>,
+    closed: bool,
+}
+
+impl BodySink {
+    pub(crate) fn with_trailers(mut self, trailers: HeaderMap) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+}
+
+impl Sink<Vec<u8>> for BodySink {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.get_mut().pending = item.into();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        while !this.pending.is_empty() {
+            this.send.reserve_capacity(this.pending.len());
+            while this.send.capacity() == 0 {
+                match ready!(this.send.poll_capacity(cx)) {
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Poll::Ready(Err(e.into())),
+                    None => return Poll::Ready(Err(anyhow!("HTTP/2 stream closed"))),
+                }
+            }
+            let n = this.send.capacity().min(this.pending.len());
+            let chunk = this.pending.split_to(n);
+            this.send.send_data(chunk, false)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        let this = self.get_mut();
+        if !this.closed {
+            this.closed = true;
+            match this.trailers.take() {
+                Some(trailers) => this.send.send_trailers(trailers)?,
+                None => this.send.send_data(Bytes::new(), true)?,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Serve the requests of an HTTP/2 connection that `accepts` lets through,
+/// answering each with `content_type` and giving its request and response
+/// bodies to `handle`. Other requests are answered with a 404.
+pub(crate) async fn serve_bodies<A, F, Fut>(
+    connection: impl AsyncRead + AsyncWrite + Unpin,
+    accepts: A,
+    content_type: &'static str,
+    handle: F,
+) -> Result<(), Error>
+where
+    A: Fn(&Request<RecvStream>) -> bool,
+    F: Fn(BodyStream, BodySink) -> Fut,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let mut connection = h2::server::handshake(connection).await?;
+    while let Some(request) = connection.accept().await {
+        let (request, mut respond) = request?;
+        if !accepts(&request) {
+            info!("{} {} is not for us", request.method(), request.uri());
+            let response = Response::builder().status(StatusCode::NOT_FOUND).body(())?;
+            respond.send_response(response, true)?;
+            continue;
+        }
+        let response = Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(())?;
+        let send = respond.send_response(response, false)?;
+        let stream = BodyStream {
+            recv: request.into_body(),
+        };
+        let sink = BodySink {
+            send,
+            pending: Bytes::new(),
+            trailers: None,
+            closed: false,
+        };
+        let session = handle(stream, sink);
+        tokio::spawn(async move {
+            session.await.unwrap_or_else(|e| info!("Error: {:?}", e));
+        });
+    }
+    Ok(())
+}
+
+/// Whether `request` is one an h2 inbound with `config` takes.
+fn h2_accepts(config: &H2Config, request: &Request<RecvStream>) -> bool {
+    let method = match &config.method {
+        Some(method) => request.method().as_str() == method,
+        None => matches!(*request.method(), Method::PUT | Method::POST),
+    };
+    let host = request.uri().host().unwrap_or_default();
+    let host = config.host.is_empty() || config.host.iter().any(|h| h.eq_ignore_ascii_case(host));
+    method && host && request.uri().path() == config.path
+}
+
+/// Serve Xray's h2 transport on an HTTP/2 connection: every request
+/// `config` accepts carries a stream, up in its body and down in the body of
+/// the response.
+pub(crate) async fn serve_h2<F, Fut>(
+    connection: impl AsyncRead + AsyncWrite + Unpin,
+    config: &H2Config,
+    handle: F,
+) -> Result<(), Error>
+where
+    F: Fn(BodyStream, BodySink) -> Fut,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let accepts = |request: &Request<RecvStream>| h2_accepts(config, request);
+    serve_bodies(connection, accepts, "application/octet-stream", handle).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_read_preface() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_preface(&mut server).await.unwrap(),
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+        tokio::io::AsyncWriteExt::write_all(&mut client, H2_PREFACE)
+            .await
+            .unwrap();
+        assert_eq!(read_preface(&mut server).await.unwrap(), H2_PREFACE);
+    }
+
+    #[tokio::test]
+    async fn test_serve_h2() {
+        let config = H2Config {
+            host: vec!["example.com".to_string()],
+            path: "/h2".to_string(),
+            method: None,
+        };
+        let (client, server) = tokio::io::duplex(65536);
+        tokio::spawn(async move {
+            serve_h2(server, &config, |mut stream, mut sink| async move {
+                while let Some(data) = stream.next().await {
+                    sink.send(data?).await?;
+                }
+                sink.close().await
+            })
+            .await
+        });
+
+        let (h2, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let mut h2 = h2.ready().await.unwrap();
+        let request = |method: Method, uri: &str| {
+            Request::builder().method(method).uri(uri).body(()).unwrap()
+        };
+
+        for (method, uri) in [
+            (Method::PUT, "https://other.example/h2"),
+            (Method::PUT, "https://example.com/other"),
+            (Method::GET, "https://example.com/h2"),
+        ] {
+            let (response, _) = h2.send_request(request(method, uri), true).unwrap();
+            assert_eq!(response.await.unwrap().status(), StatusCode::NOT_FOUND);
+            h2 = h2.ready().await.unwrap();
+        }
+
+        let (response, mut send) = h2
+            .send_request(request(Method::POST, "https://example.com:443/h2"), false)
+            .unwrap();
+        send.send_data(Bytes::from_static(b"hello"), true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut received = vec![];
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, b"hello");
+    }
+}
@@ -1,60 +1,361 @@
 mod buffer_parser;
-// mod config;
+mod config;
+mod fallback;
+mod grpc;
+mod handover;
+mod http;
+mod http2;
+mod listener;
+mod mux;
+mod proxy_protocol;
+mod quic;
+mod site;
+mod splithttp;
 mod tcp;
+mod tls;
 mod vless;
 mod websocket;
 mod write_ext;
 
 use anyhow::{anyhow, Error};
-use std::future::ready;
-use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
-use websocket::handle_stream_sink;
+use fallback::Prefixed;
+use grpc::serve_gun;
+use http::{head_len, read_request_head, respond_status, respond_upgrade, RequestHead};
+use http2::{read_preface, serve_h2, H2_PREFACE};
+use listener::Connection;
+use proxy_protocol::resolve_peer;
+use quic::{serve_quic, server_endpoint};
+use splithttp::SplitHttp;
+use std::{future::ready, net::SocketAddr, sync::Arc};
+use tls::MaybeTlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    http::header::SEC_WEBSOCKET_PROTOCOL,
+};
+use websocket::{client_addr, handle_stream_sink, EarlyData, Routed, WsRoutes, WsSession};
 
 pub use buffer_parser::*;
+pub use config::*;
+pub use fallback::Fallbacks;
 use futures::{SinkExt, StreamExt};
-// pub use config::*;
+pub use grpc::{GunMessage, GunParseError};
+pub use handover::{drain, upgrade, HandedOver, Handover, ListenerRole};
+pub use listener::Listener;
+pub use mux::*;
+pub use proxy_protocol::*;
+pub use site::*;
 
 use crate::buffer_parser::Protocol;
 use tracing::info;
 
 pub use vless::*;
 
-pub async fn run_vless_over_tcp() -> Result<(), Error> {
-    let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:34434").await?;
+/// Run `inbound` until its listener fails, on `listener` if one is already
+/// open for it, such as one passed by systemd, or else on its `listen`
+/// address. `site` is the built-in site fallbacks may point to.
+pub async fn run_inbound(
+    inbound: InboundConfig,
+    listener: Option<Listener>,
+    users: Arc<VlessUsers>,
+    site: Option<SiteConfig>,
+) -> Result<(), Error> {
+    info!("starting inbound {}", inbound.tag);
+    let tls = inbound.tls.as_ref().map(TlsConfig::acceptor).transpose()?;
+    let fallbacks = Fallbacks::new(inbound.fallbacks, site.map(|s| s.root));
+    let proxy_protocol = inbound.proxy_protocol;
+    let outbound_proxy_protocol = inbound.outbound_proxy_protocol.map(Arc::new);
+    if listener.is_some() && matches!(inbound.transport, InboundTransport::Quic) {
+        return Err(anyhow!("QUIC cannot take a stream socket passed to it"));
+    }
+    let (listen, mode) = (&inbound.listen, inbound.listen_mode);
+    let bind = || async move {
+        match listener {
+            Some(listener) => Ok(listener),
+            None => Listener::bind(listen, mode).await,
+        }
+    };
+    match inbound.transport {
+        InboundTransport::Tcp => {
+            run_vless_over_tcp(
+                bind().await?,
+                users,
+                proxy_protocol,
+                outbound_proxy_protocol,
+                tls,
+                fallbacks,
+            )
+            .await
+        }
+        InboundTransport::Ws(ws) => {
+            let routes = WsRoutes::new(inbound.routes, users);
+            run_vless_over_tungstenite_ws(
+                bind().await?,
+                routes,
+                proxy_protocol,
+                outbound_proxy_protocol,
+                tls,
+                fallbacks,
+                ws,
+            )
+            .await
+        }
+        InboundTransport::HttpUpgrade(ws) => {
+            let routes = WsRoutes::new(inbound.routes, users);
+            run_vless_over_httpupgrade(
+                bind().await?,
+                routes,
+                proxy_protocol,
+                outbound_proxy_protocol,
+                tls,
+                fallbacks,
+                ws,
+            )
+            .await
+        }
+        InboundTransport::SplitHttp(split_http) => {
+            run_vless_over_split_http(
+                bind().await?,
+                users,
+                proxy_protocol,
+                outbound_proxy_protocol,
+                tls,
+                fallbacks,
+                split_http,
+            )
+            .await
+        }
+        InboundTransport::Quic => {
+            let tls = inbound
+                .tls
+                .as_ref()
+                .ok_or_else(|| anyhow!("QUIC needs TLS"))?
+                .server_config()?;
+            let ListenAddr::Inet(listen) = inbound.listen else {
+                return Err(anyhow!("QUIC cannot listen on a Unix socket"));
+            };
+            let endpoint = server_endpoint(listen, tls)?;
+            serve_quic(endpoint, users, outbound_proxy_protocol, fallbacks).await
+        }
+        InboundTransport::Grpc(grpc) => {
+            run_vless_over_grpc(
+                bind().await?,
+                users,
+                proxy_protocol,
+                outbound_proxy_protocol,
+                tls,
+                fallbacks,
+                grpc,
+            )
+            .await
+        }
+        InboundTransport::H2(h2) => {
+            run_vless_over_h2(
+                bind().await?,
+                users,
+                proxy_protocol,
+                outbound_proxy_protocol,
+                tls,
+                fallbacks,
+                h2,
+            )
+            .await
+        }
+    }
+}
+
+/// Serve VLESS over TCP. With `proxy_protocol` set, connections from the
+/// load balancers it names start with a PROXY header giving the client. With
+/// `outbound_proxy_protocol` set, connections to the targets it names start
+/// with one.
+pub async fn run_vless_over_tcp(
+    listener: Listener,
+    users: Arc<VlessUsers>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
+    fallbacks: Fallbacks,
+) -> Result<(), Error> {
+    info!("started listening on {}", listener.local_addr()?);
+    let fallbacks = Arc::new(fallbacks);
+    let proxy_protocol = Arc::new(proxy_protocol);
 
-    while let Ok((incoming, addr)) = tcp_listener.accept().await {
-        info!("New connection from: {} -> ", addr);
+    while let Ok((mut incoming, addr)) = listener.accept().await {
+        let users = users.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let tls = tls.clone();
+        let fallbacks = fallbacks.clone();
+        let outbound_proxy_protocol = outbound_proxy_protocol.clone();
         tokio::spawn(async move {
-            let proto = VlessProtocol::new("test");
-            Protocol::handle(&proto, incoming, addr)
-                .await
-                .unwrap_or_else(|e| info!("Error: {:?}", e));
+            async {
+                let addr =
+                    resolve_peer(&mut incoming, addr, proxy_protocol.as_ref().as_ref()).await?;
+                let incoming = MaybeTlsStream::accept(incoming, tls.as_ref()).await?;
+                let proto = VlessProtocol::new(users)
+                    .with_fallbacks(fallbacks, incoming.fallback_info())
+                    .with_outbound_proxy_protocol(outbound_proxy_protocol)
+                    .with_raw_switch(incoming.raw_switch());
+                Protocol::handle(&proto, incoming, addr).await
+            }
+            .await
+            .unwrap_or_else(|e| info!("Error: {:?}", e));
         });
     }
 
     Ok(())
 }
 
-pub async fn run_vless_over_tungstenite_ws() -> Result<(), Error> {
-    let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:34080").await?;
-    info!("started listening on {}", tcp_listener.local_addr()?);
-
-    while let Ok((incoming, addr)) = tcp_listener.accept().await {
-        info!("New connection from: {} -> ", addr);
-        let cb = |req: &Request, resp: Response| {
-            let p = req.uri().path();
-            info!("{}", p);
+/// An upgrade request accepted on a ws or httpupgrade inbound.
+struct Upgrade {
+    incoming: MaybeTlsStream,
+    /// The request head, and whatever was read past it.
+    head: Vec<u8>,
+    addr: SocketAddr,
+    session: WsSession,
+    early_data: Option<EarlyData>,
+}
 
-            Ok(resp)
+/// Read the upgrade request off a new connection from `addr`. Requests that
+/// are not upgrades for `ws.path`, or that `routes` refuse, are served here
+/// and `None` is returned.
+async fn accept_upgrade(
+    mut incoming: Connection,
+    addr: SocketAddr,
+    proxy_protocol: Option<&ProxyProtocolConfig>,
+    tls: Option<&TlsAcceptor>,
+    fallbacks: &Fallbacks,
+    routes: &WsRoutes,
+    ws: &WsConfig,
+) -> Result<Option<Upgrade>, Error> {
+    let addr = resolve_peer(&mut incoming, addr, proxy_protocol).await?;
+    let mut incoming = MaybeTlsStream::accept(incoming, tls).await?;
+    let head = read_request_head(&mut incoming).await?;
+    let request = RequestHead::parse(&head)?;
+    let addr = client_addr(addr, &request, &ws.trusted_proxies, &ws.real_ip_header);
+    let upgrade =
+        request.is_websocket_upgrade() && ws.path.as_deref().is_none_or(|p| p == request.path());
+    if upgrade {
+        let session = match routes.route(&request) {
+            Routed::Session(session) => session,
+            Routed::Rejected => {
+                info!("{} from {} refused by route", request.target, addr);
+                respond_status(incoming, "404 Not Found").await?;
+                return Ok(None);
+            }
+            Routed::Full => {
+                info!("{} from {} over the route limit", request.target, addr);
+                respond_status(incoming, "503 Service Unavailable").await?;
+                return Ok(None);
+            }
         };
+        let early_data = EarlyData::from_request(&request, ws.early_data_query.as_deref())?;
+        return Ok(Some(Upgrade {
+            incoming,
+            head,
+            addr,
+            session,
+            early_data,
+        }));
+    }
+    info!(
+        "{} {} from {} is not for us",
+        request.method, request.target, addr
+    );
+    fall_back(incoming, &head, addr, fallbacks).await?;
+    Ok(None)
+}
+
+/// Hand a request from `addr` that is not for the inbound to `fallbacks`,
+/// or answer it with a 404 if there are none.
+async fn fall_back(
+    incoming: MaybeTlsStream,
+    head: &[u8],
+    addr: SocketAddr,
+    fallbacks: &Fallbacks,
+) -> Result<(), Error> {
+    if fallbacks.is_empty() {
+        respond_status(incoming, "404 Not Found").await
+    } else {
+        let info = incoming.fallback_info();
+        fallbacks.serve(incoming, head, &info, addr).await
+    }
+}
 
-        let ws_stream = tokio_tungstenite::accept_hdr_async(incoming, cb).await?;
+/// Serve VLESS over WebSocket. With a `path` set only requests for it are
+/// upgraded, any other request goes to `fallbacks` and is answered with a 404
+/// if none matches, so the listener can double as an ordinary website.
+/// `routes` then settle the users, outbound PROXY header and connection
+/// limit of each upgraded session.
+///
+/// Early data sent along with the upgrade request, in `Sec-WebSocket-Protocol`
+/// or the `early_data_query` parameter, is read before the first frame. The
+/// client address is taken from `real_ip_header` on requests coming from
+/// `trusted_proxies`, after any PROXY header as in `run_vless_over_tcp`.
+pub async fn run_vless_over_tungstenite_ws(
+    listener: Listener,
+    routes: WsRoutes,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
+    fallbacks: Fallbacks,
+    ws: WsConfig,
+) -> Result<(), Error> {
+    info!("started listening on {}", listener.local_addr()?);
+    let routes = Arc::new(routes);
+    let fallbacks = Arc::new(fallbacks);
+    let ws = Arc::new(ws);
+    let proxy_protocol = Arc::new(proxy_protocol);
+
+    while let Ok((incoming, addr)) = listener.accept().await {
+        let routes = routes.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let tls = tls.clone();
+        let fallbacks = fallbacks.clone();
+        let outbound_proxy_protocol = outbound_proxy_protocol.clone();
+        let ws = ws.clone();
         tokio::spawn(async move {
+            let upgrade = accept_upgrade(
+                incoming,
+                addr,
+                proxy_protocol.as_ref().as_ref(),
+                tls.as_ref(),
+                &fallbacks,
+                &routes,
+                &ws,
+            );
+            let Upgrade {
+                incoming,
+                head,
+                addr,
+                session,
+                early_data,
+            } = match upgrade.await {
+                Ok(Some(upgrade)) => upgrade,
+                Ok(None) => return,
+                Err(e) => return info!("Error: {:?}", e),
+            };
+            let protocol = early_data.as_ref().and_then(|e| e.protocol.clone());
+            #[allow(clippy::result_large_err)]
+            let cb = |req: &Request, mut resp: Response| {
+                let p = req.uri().path();
+                info!("{}", p);
+                // The client only accepts the upgrade if the protocol it
+                // offered, here the early data itself, is echoed back.
+                if let Some(protocol) = protocol {
+                    resp.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+                }
+
+                Ok(resp)
+            };
+            let incoming = Prefixed::new(head, incoming);
+            let ws_stream = match tokio_tungstenite::accept_hdr_async(incoming, cb).await {
+                Ok(ws_stream) => ws_stream,
+                Err(e) => return info!("Error: {:?}", e),
+            };
             let (sink, stream) = ws_stream.split();
             let stream = stream
                 .filter(|msg| {
                     msg.as_ref()
-                        .clone()
                         .map(|msg| ready(msg.is_binary()))
                         .unwrap_or(ready(true))
                 })
@@ -62,12 +363,254 @@ pub async fn run_vless_over_tungstenite_ws() -> Result<(), Error> {
                     msg.map(|msg| msg.into_data())
                         .map_err(|e| anyhow!("Error reading from ws: {:?}", e))
                 });
+            let stream = futures::stream::iter(early_data.map(|e| Ok(e.data))).chain(stream);
             let sink = sink.with(|msg: Vec<u8>| {
                 futures::future::ready(Ok(tokio_tungstenite::tungstenite::Message::Binary(msg)))
             });
-            handle_stream_sink(stream, sink, addr)
+            let outbound_proxy_protocol =
+                session.outbound_proxy_protocol.or(outbound_proxy_protocol);
+            handle_stream_sink(stream, sink, addr, session.users, outbound_proxy_protocol)
                 .await
                 .unwrap_or_else(|e| info!("Error: {:?}", e));
+            drop(session.slot);
+        });
+    }
+
+    Ok(())
+}
+
+/// Serve VLESS over HTTPUpgrade: the handshake of `run_vless_over_tungstenite_ws`,
+/// with the same paths, routes, fallbacks and early data, after which the
+/// upgraded connection carries VLESS as is, without WebSocket framing.
+pub async fn run_vless_over_httpupgrade(
+    listener: Listener,
+    routes: WsRoutes,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
+    fallbacks: Fallbacks,
+    ws: WsConfig,
+) -> Result<(), Error> {
+    info!("started listening on {}", listener.local_addr()?);
+    let routes = Arc::new(routes);
+    let fallbacks = Arc::new(fallbacks);
+    let ws = Arc::new(ws);
+    let proxy_protocol = Arc::new(proxy_protocol);
+
+    while let Ok((incoming, addr)) = listener.accept().await {
+        let routes = routes.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let tls = tls.clone();
+        let fallbacks = fallbacks.clone();
+        let outbound_proxy_protocol = outbound_proxy_protocol.clone();
+        let ws = ws.clone();
+        tokio::spawn(async move {
+            async {
+                let upgrade = accept_upgrade(
+                    incoming,
+                    addr,
+                    proxy_protocol.as_ref().as_ref(),
+                    tls.as_ref(),
+                    &fallbacks,
+                    &routes,
+                    &ws,
+                )
+                .await?;
+                let Some(Upgrade {
+                    mut incoming,
+                    head,
+                    addr,
+                    session,
+                    early_data,
+                }) = upgrade
+                else {
+                    return Ok(());
+                };
+                let protocol = early_data.as_ref().and_then(|e| e.protocol.as_ref());
+                respond_upgrade(&mut incoming, protocol.map(|p| p.as_bytes())).await?;
+                // Early data comes first, then anything sent right after
+                // the request head.
+                let mut initial = early_data.map(|e| e.data).unwrap_or_default();
+                initial.extend_from_slice(&head[head_len(&head).unwrap_or(head.len())..]);
+                let proto = VlessProtocol::new(session.users).with_outbound_proxy_protocol(
+                    session.outbound_proxy_protocol.or(outbound_proxy_protocol),
+                );
+                let result = Protocol::handle(&proto, Prefixed::new(initial, incoming), addr).await;
+                drop(session.slot);
+                result
+            }
+            .await
+            .unwrap_or_else(|e| info!("Error: {:?}", e));
+        });
+    }
+
+    Ok(())
+}
+
+/// Serve VLESS over SplitHTTP, with sessions under `path` made of a
+/// streaming GET and numbered POSTs that may come over any number of
+/// connections. Over TLS, HTTP/2 is used when negotiated with ALPN; HTTP/1.1
+/// requests for other paths go to `fallbacks`.
+pub async fn run_vless_over_split_http(
+    listener: Listener,
+    users: Arc<VlessUsers>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
+    fallbacks: Fallbacks,
+    config: SplitHttpConfig,
+) -> Result<(), Error> {
+    info!("started listening on {}", listener.local_addr()?);
+    let split_http = Arc::new(SplitHttp::new(config, users, outbound_proxy_protocol));
+    let fallbacks = Arc::new(fallbacks);
+    let proxy_protocol = Arc::new(proxy_protocol);
+
+    while let Ok((mut incoming, addr)) = listener.accept().await {
+        let split_http = split_http.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let tls = tls.clone();
+        let fallbacks = fallbacks.clone();
+        tokio::spawn(async move {
+            async {
+                let addr =
+                    resolve_peer(&mut incoming, addr, proxy_protocol.as_ref().as_ref()).await?;
+                let mut incoming = MaybeTlsStream::accept(incoming, tls.as_ref()).await?;
+                if incoming.alpn() == Some(b"h2") {
+                    return split_http.serve_connection(incoming, addr, true).await;
+                }
+                let head = read_request_head(&mut incoming).await?;
+                let request = RequestHead::parse(&head)?;
+                if split_http.serves(request.path()) {
+                    let incoming = Prefixed::new(head, incoming);
+                    return split_http.serve_connection(incoming, addr, false).await;
+                }
+                info!(
+                    "{} {} from {} is not for us",
+                    request.method, request.target, addr
+                );
+                fall_back(incoming, &head, addr, &fallbacks).await
+            }
+            .await
+            .unwrap_or_else(|e| info!("Error: {:?}", e));
+        });
+    }
+
+    Ok(())
+}
+
+/// Serve VLESS over gRPC, as the `Tun` and `TunMulti` streaming calls of
+/// `service_name`. The connection must speak HTTP/2, negotiated with ALPN
+/// under TLS or with prior knowledge otherwise; anything else goes to
+/// `fallbacks`.
+pub async fn run_vless_over_grpc(
+    listener: Listener,
+    users: Arc<VlessUsers>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
+    fallbacks: Fallbacks,
+    grpc: GrpcConfig,
+) -> Result<(), Error> {
+    info!("started listening on {}", listener.local_addr()?);
+    let fallbacks = Arc::new(fallbacks);
+    let proxy_protocol = Arc::new(proxy_protocol);
+    let grpc = Arc::new(grpc);
+
+    while let Ok((mut incoming, addr)) = listener.accept().await {
+        let users = users.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let tls = tls.clone();
+        let fallbacks = fallbacks.clone();
+        let outbound_proxy_protocol = outbound_proxy_protocol.clone();
+        let grpc = grpc.clone();
+        tokio::spawn(async move {
+            async {
+                let addr =
+                    resolve_peer(&mut incoming, addr, proxy_protocol.as_ref().as_ref()).await?;
+                let mut incoming = MaybeTlsStream::accept(incoming, tls.as_ref()).await?;
+                let preface = read_preface(&mut incoming).await?;
+                if preface != H2_PREFACE {
+                    if fallbacks.is_empty() {
+                        return Err(anyhow!("{} does not speak HTTP/2", addr));
+                    }
+                    let info = incoming.fallback_info();
+                    return fallbacks.serve(incoming, &preface, &info, addr).await;
+                }
+                serve_gun(
+                    Prefixed::new(preface, incoming),
+                    &grpc.service_name,
+                    |stream, sink| {
+                        handle_stream_sink(
+                            stream,
+                            sink,
+                            addr,
+                            users.clone(),
+                            outbound_proxy_protocol.clone(),
+                        )
+                    },
+                )
+                .await
+            }
+            .await
+            .unwrap_or_else(|e| info!("Error: {:?}", e));
+        });
+    }
+
+    Ok(())
+}
+
+/// Serve VLESS over Xray's h2 transport, carried up in the body of each
+/// HTTP/2 request `h2` accepts and down in the body of its response. The
+/// connection must negotiate HTTP/2 with ALPN; anything else goes to
+/// `fallbacks`.
+pub async fn run_vless_over_h2(
+    listener: Listener,
+    users: Arc<VlessUsers>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
+    fallbacks: Fallbacks,
+    h2: H2Config,
+) -> Result<(), Error> {
+    let tls = tls.ok_or_else(|| anyhow!("h2 needs TLS"))?;
+    info!("started listening on {}", listener.local_addr()?);
+    let fallbacks = Arc::new(fallbacks);
+    let proxy_protocol = Arc::new(proxy_protocol);
+    let h2 = Arc::new(h2);
+
+    while let Ok((mut incoming, addr)) = listener.accept().await {
+        let users = users.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let tls = tls.clone();
+        let fallbacks = fallbacks.clone();
+        let outbound_proxy_protocol = outbound_proxy_protocol.clone();
+        let h2 = h2.clone();
+        tokio::spawn(async move {
+            async {
+                let addr =
+                    resolve_peer(&mut incoming, addr, proxy_protocol.as_ref().as_ref()).await?;
+                let mut incoming = MaybeTlsStream::accept(incoming, Some(&tls)).await?;
+                let preface = read_preface(&mut incoming).await?;
+                if preface != H2_PREFACE {
+                    if fallbacks.is_empty() {
+                        return Err(anyhow!("{} does not speak HTTP/2", addr));
+                    }
+                    let info = incoming.fallback_info();
+                    return fallbacks.serve(incoming, &preface, &info, addr).await;
+                }
+                serve_h2(Prefixed::new(preface, incoming), &h2, |stream, sink| {
+                    handle_stream_sink(
+                        stream,
+                        sink,
+                        addr,
+                        users.clone(),
+                        outbound_proxy_protocol.clone(),
+                    )
+                })
+                .await
+            }
+            .await
+            .unwrap_or_else(|e| info!("Error: {:?}", e));
         });
     }
 
@@ -0,0 +1,367 @@
+// Inbound listeners, on TCP or on a Unix socket. A front server on the same
+// host can reach an inbound through a Unix socket, whose permissions decide
+// which local users may connect, instead of a loopback port open to all.
+//
+// Listeners may also come open from systemd socket activation, so that
+// privileged ports need no root and the listen queue outlives restarts, or
+// from the server an upgrade replaces. Accepted connections are counted, so
+// a replaced server knows when the last of its sessions is over.
+
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    path::Path,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Context as _, Error};
+use futures::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tracing::info;
+
+use crate::ListenAddr;
+
+/// The address given to peers on a Unix socket. They are on this host, so
+/// the `proxy_protocol` and `trusted_proxies` settings naming the loopback
+/// address apply to them as they did when the front server used a port.
+pub(crate) const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Connections accepted and not yet closed, on all listeners.
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many accepted connections are still open.
+pub(crate) fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// The first file descriptor passed with socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A bound listener of an inbound.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Remove what a previous run left at `path`, refusing to touch anything
+/// but a socket.
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("removing stale socket {}", path.display())),
+        Ok(_) => Err(anyhow!("{} exists and is not a socket", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The names and file descriptors of the sockets passed with socket
+/// activation, from the values of `LISTEN_PID`, `LISTEN_FDS` and
+/// `LISTEN_FDNAMES`. They are for the process with id `pid` only.
+fn passed_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(String, RawFd)>, Error> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(vec![]);
+    };
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        return Ok(vec![]);
+    }
+    let count: RawFd = listen_fds
+        .parse()
+        .map_err(|_| anyhow!("invalid LISTEN_FDS `{}`", listen_fds))?;
+    let names: Vec<&str> = match listen_fdnames {
+        Some(names) => names.split(':').collect(),
+        None => vec![],
+    };
+    if listen_fdnames.is_some() && names.len() != count as usize {
+        return Err(anyhow!(
+            "LISTEN_FDNAMES names {} sockets but LISTEN_FDS passes {}",
+            names.len(),
+            count
+        ));
+    }
+    Ok((0..count)
+        .map(|i| {
+            // systemd names sockets "unknown" unless told otherwise.
+            let name = names.get(i as usize).copied().unwrap_or("unknown");
+            (name.to_string(), LISTEN_FDS_START + i)
+        })
+        .collect())
+}
+
+impl Listener {
+    /// Take the listening sockets passed with systemd socket activation, by
+    /// the names `FileDescriptorName=` gave them: the tags of the inbounds
+    /// they are for. Empty when not socket activated.
+    pub fn activated() -> Result<HashMap<String, Self>, Error> {
+        let var = |name| std::env::var(name).ok();
+        let fds = passed_fds(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            var("LISTEN_FDNAMES").as_deref(),
+            std::process::id(),
+        )?;
+        let mut listeners = HashMap::new();
+        for (name, fd) in fds {
+            // SAFETY: systemd passes these descriptors to this process alone,
+            // and nothing else in it takes them.
+            let passed = unsafe { BorrowedFd::borrow_raw(fd) };
+            // They stay open across exec, unlike their duplicates.
+            let owned = passed
+                .try_clone_to_owned()
+                .with_context(|| format!("socket `{}`", name))?;
+            // SAFETY: as above, and only the duplicate is used from here on.
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+            let listener = Self::from_fd(owned).with_context(|| format!("socket `{}`", name))?;
+            if listeners.insert(name.clone(), listener).is_some() {
+                return Err(anyhow!("more than one socket named `{}`", name));
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// A listener on `fd`, a listening socket on TCP or Unix.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        let tcp = std::net::TcpListener::from(fd);
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            return Ok(Self::Tcp(TcpListener::from_std(tcp)?));
+        }
+        let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+        unix.local_addr()
+            .context("not a TCP or Unix listening socket")?;
+        unix.set_nonblocking(true)?;
+        Ok(Self::Unix(UnixListener::from_std(unix)?))
+    }
+
+    /// Listen on `listen`. A Unix socket replaces the one a previous run
+    /// left behind and gets `mode` for permissions if set.
+    pub async fn bind(listen: &ListenAddr, mode: Option<u32>) -> Result<Self, Error> {
+        match listen {
+            ListenAddr::Inet(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("binding {}", path.display()))?;
+                if let Some(mode) = mode {
+                    std::fs::set_permissions(path, Permissions::from_mode(mode))
+                        .with_context(|| format!("setting the mode of {}", path.display()))?;
+                }
+                Ok(Self::Unix(listener))
+            }
+        }
+    }
+
+    /// Where the listener is listening.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(ListenAddr::Inet),
+            Self::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or(Path::new("(unnamed)"));
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+
+    /// The connections accepted from here on, for servers taking a stream.
+    pub(crate) fn incoming(self) -> impl Stream<Item = io::Result<Connection>> {
+        futures::stream::unfold(self, |listener| async {
+            let connection = listener.accept().await.map(|(connection, _)| connection);
+            Some((connection, listener))
+        })
+    }
+
+    /// Accept a connection, with the address of its peer: the client on TCP,
+    /// `UNIX_PEER` on a Unix socket, whose peer is logged by process instead.
+    pub(crate) async fn accept(&self) -> io::Result<(Connection, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                info!("New connection from: {} -> ", addr);
+                Ok((stream.into(), addr))
+            }
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                match stream.peer_cred() {
+                    Ok(cred) => info!(
+                        "New connection from: pid {} uid {} on unix socket -> ",
+                        cred.pid().map_or("?".to_string(), |pid| pid.to_string()),
+                        cred.uid()
+                    ),
+                    Err(_) => info!("New connection from: unix socket -> "),
+                }
+                Ok((stream.into(), UNIX_PEER))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// An accepted connection, on TCP or on a Unix socket, counted as open
+/// until dropped.
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Self {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Connection {
+    fn from(stream: UnixStream) -> Self {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self::Unix(stream)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Connection {
+    /// The address the client connected to, if on TCP.
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.local_addr().ok(),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_passed_fds() {
+        let fds = passed_fds(Some("42"), Some("2"), Some("vless-tcp:vless-ws"), 42).unwrap();
+        assert_eq!(
+            fds,
+            [("vless-tcp".to_string(), 3), ("vless-ws".to_string(), 4)]
+        );
+        let fds = passed_fds(Some("42"), Some("1"), None, 42).unwrap();
+        assert_eq!(fds, [("unknown".to_string(), 3)]);
+        // Meant for another process, or not socket activated at all.
+        assert!(passed_fds(Some("41"), Some("1"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(passed_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(passed_fds(Some("42"), Some("two"), None, 42).is_err());
+        assert!(passed_fds(Some("42"), Some("2"), Some("vless-tcp"), 42).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_from_fd() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = Listener::from_fd(tcp.into()).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), ListenAddr::Inet(addr));
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(Listener::from_fd(file.into()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = std::env::temp_dir().join(format!("rocks-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vless.sock");
+        let listen = ListenAddr::Unix(path.clone());
+
+        // Binding again replaces the socket left behind.
+        drop(Listener::bind(&listen, None).await.unwrap());
+        let listener = Listener::bind(&listen, Some(0o600)).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), listen);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut accepted, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, UNIX_PEER);
+        assert_eq!(accepted.local_addr(), None);
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Anything but a socket is left alone.
+        let file = dir.join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(Listener::bind(&ListenAddr::Unix(file), None).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use thiserror::Error;
+
+use crate::{
+    BufferFormer, BufferParseResult, BufferParser, InsufficientBuffer, ProxyAddressWithPort,
+};
+
+/// The largest metadata section accepted in a frame.
+pub const MAX_METADATA_SIZE: usize = 512;
+/// The largest payload a single frame can carry.
+pub const MAX_FRAME_DATA: usize = u16::MAX as usize;
+
+const OPTION_DATA: u8 = 0x01;
+const OPTION_ERROR: u8 = 0x02;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MuxFrameParseError {
+    #[error("Invalid metadata length")]
+    InvalidMetadataLength,
+    #[error("Invalid session status")]
+    InvalidSessionStatus,
+    #[error("Invalid network")]
+    InvalidNetwork,
+    #[error("Invalid address")]
+    InvalidAddress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxSessionStatus {
+    New,
+    Keep,
+    End,
+    KeepAlive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxNetwork {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug)]
+pub struct MuxTarget<'a> {
+    pub network: MuxNetwork,
+    pub address: ProxyAddressWithPort<'a>,
+}
+
+/// A Mux.Cool frame: a metadata section, prefixed with its length, followed
+/// by an optional length-prefixed payload.
+///
+/// New frames always carry a target; Keep frames may carry one too, which is
+/// how UDP packets address individual destinations.
+#[derive(Debug)]
+pub struct MuxFrame<'a> {
+    pub session_id: u16,
+    pub status: MuxSessionStatus,
+    pub error: bool,
+    pub target: Option<MuxTarget<'a>>,
+    pub global_id: Option<[u8; 8]>,
+    pub data: Option<&'a [u8]>,
+}
+
+impl<'a> MuxFrame<'a> {
+    fn metadata_size(&self) -> usize {
+        4 + self
+            .target
+            .as_ref()
+            .map(|t| 1 + t.address.size())
+            .unwrap_or(0)
+            + self.global_id.map(|_| 8).unwrap_or(0)
+    }
+}
+
+impl<'a> BufferParser<'a> for MuxFrame<'a> {
+    type Error = MuxFrameParseError;
+    type ParseOptions = ();
+
+    fn parse_with_options<'b>(buffer: &'b [u8], _: ()) -> BufferParseResult<Self, Self::Error>
+    where
+        Self: Sized,
+        'b: 'a,
+    {
+        if buffer.len() < 2 {
+            return BufferParseResult::Incomplete {
+                needed: 2 - buffer.len(),
+            };
+        }
+        let meta_len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+        if !(4..=MAX_METADATA_SIZE).contains(&meta_len) {
+            return BufferParseResult::Error(MuxFrameParseError::InvalidMetadataLength);
+        }
+        if buffer.len() < 2 + meta_len {
+            return BufferParseResult::Incomplete {
+                needed: 2 + meta_len - buffer.len(),
+            };
+        }
+        let meta = &buffer[2..2 + meta_len];
+        let session_id = u16::from_be_bytes([meta[0], meta[1]]);
+        let status = match meta[2] {
+            0x01 => MuxSessionStatus::New,
+            0x02 => MuxSessionStatus::Keep,
+            0x03 => MuxSessionStatus::End,
+            0x04 => MuxSessionStatus::KeepAlive,
+            _ => return BufferParseResult::Error(MuxFrameParseError::InvalidSessionStatus),
+        };
+        let option = meta[3];
+
+        let mut target = None;
+        let mut global_id = None;
+        if status == MuxSessionStatus::New || (status == MuxSessionStatus::Keep && meta_len > 4) {
+            if meta_len < 8 {
+                return BufferParseResult::Error(MuxFrameParseError::InvalidMetadataLength);
+            }
+            let network = match meta[4] {
+                0x01 => MuxNetwork::Tcp,
+                0x02 => MuxNetwork::Udp,
+                _ => return BufferParseResult::Error(MuxFrameParseError::InvalidNetwork),
+            };
+            let (address, size) = match ProxyAddressWithPort::parse(&meta[5..]) {
+                BufferParseResult::Parsed { value, size } => (value, size),
+                _ => return BufferParseResult::Error(MuxFrameParseError::InvalidAddress),
+            };
+            let rest = &meta[5 + size..];
+            if status == MuxSessionStatus::New
+                && network == MuxNetwork::Udp
+                && option & OPTION_DATA != 0
+                && rest.len() >= 8
+            {
+                global_id = Some(rest[..8].try_into().unwrap());
+            }
+            target = Some(MuxTarget { network, address });
+        }
+
+        let mut size = 2 + meta_len;
+        let mut data = None;
+        if option & OPTION_DATA != 0 {
+            if buffer.len() < size + 2 {
+                return BufferParseResult::Incomplete {
+                    needed: size + 2 - buffer.len(),
+                };
+            }
+            let data_len = u16::from_be_bytes([buffer[size], buffer[size + 1]]) as usize;
+            size += 2;
+            if buffer.len() < size + data_len {
+                return BufferParseResult::Incomplete {
+                    needed: size + data_len - buffer.len(),
+                };
+            }
+            data = Some(&buffer[size..size + data_len]);
+            size += data_len;
+        }
+
+        BufferParseResult::Parsed {
+            value: MuxFrame {
+                session_id,
+                status,
+                error: option & OPTION_ERROR != 0,
+                target,
+                global_id,
+                data,
+            },
+            size,
+        }
+    }
+}
+
+impl<'a> BufferFormer for MuxFrame<'a> {
+    type Error = InsufficientBuffer;
+    type FormingOptions = ();
+
+    fn size_with_option(&self, _: &Self::FormingOptions) -> usize {
+        2 + self.metadata_size() + self.data.map(|d| 2 + d.len()).unwrap_or(0)
+    }
+
+    fn form_with_option<'b>(
+        &'b self,
+        buffer: &'b mut [u8],
+        _: &Self::FormingOptions,
+    ) -> Result<usize, Self::Error> {
+        if buffer.len() < self.size() || self.data.is_some_and(|d| d.len() > MAX_FRAME_DATA) {
+            return Err(InsufficientBuffer);
+        }
+        buffer[..2].copy_from_slice(&(self.metadata_size() as u16).to_be_bytes());
+        buffer[2..4].copy_from_slice(&self.session_id.to_be_bytes());
+        buffer[4] = match self.status {
+            MuxSessionStatus::New => 0x01,
+            MuxSessionStatus::Keep => 0x02,
+            MuxSessionStatus::End => 0x03,
+            MuxSessionStatus::KeepAlive => 0x04,
+        };
+        buffer[5] = if self.data.is_some() { OPTION_DATA } else { 0 }
+            | if self.error { OPTION_ERROR } else { 0 };
+        let mut offset = 6;
+        if let Some(target) = &self.target {
+            buffer[offset] = match target.network {
+                MuxNetwork::Tcp => 0x01,
+                MuxNetwork::Udp => 0x02,
+            };
+            offset += 1;
+            offset += target.address.form(&mut buffer[offset..])?;
+        }
+        if let Some(global_id) = &self.global_id {
+            buffer[offset..offset + 8].copy_from_slice(global_id);
+            offset += 8;
+        }
+        if let Some(data) = self.data {
+            buffer[offset..offset + 2].copy_from_slice(&(data.len() as u16).to_be_bytes());
+            offset += 2;
+            buffer[offset..offset + data.len()].copy_from_slice(data);
+            offset += data.len();
+        }
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProxyAddress;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_new_tcp() {
+        let buffer = [
+            0x00, 0x0c, // metadata length
+            0x00, 0x01, 0x01, 0x01, // session 1, New, data
+            0x01, 0x01, 0xbb, 0x01, 127, 0, 0, 1, // TCP 127.0.0.1:443
+            0x00, 0x02, b'h', b'i', // data
+        ];
+        match MuxFrame::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(size, buffer.len());
+                assert_eq!(value.session_id, 1);
+                assert_eq!(value.status, MuxSessionStatus::New);
+                let target = value.target.unwrap();
+                assert_eq!(target.network, MuxNetwork::Tcp);
+                assert_eq!(target.address.port, 443);
+                assert!(value.global_id.is_none());
+                assert_eq!(value.data, Some(&b"hi"[..]));
+            }
+            r => panic!("Failed to parse frame: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_keep_without_target() {
+        let buffer = [0x00, 0x04, 0x00, 0x07, 0x02, 0x01, 0x00, 0x01, 0xff];
+        match MuxFrame::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(size, buffer.len());
+                assert_eq!(value.session_id, 7);
+                assert_eq!(value.status, MuxSessionStatus::Keep);
+                assert!(value.target.is_none());
+                assert_eq!(value.data, Some(&[0xff][..]));
+            }
+            r => panic!("Failed to parse frame: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_end_with_error() {
+        let buffer = [0x00, 0x04, 0x00, 0x07, 0x03, 0x02];
+        match MuxFrame::parse(&buffer) {
+            BufferParseResult::Parsed { value, .. } => {
+                assert_eq!(value.status, MuxSessionStatus::End);
+                assert!(value.error);
+                assert!(value.data.is_none());
+            }
+            r => panic!("Failed to parse frame: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_incomplete_data() {
+        let buffer = [0x00, 0x04, 0x00, 0x07, 0x02, 0x01, 0x00, 0x03, 0xff];
+        match MuxFrame::parse(&buffer) {
+            BufferParseResult::Incomplete { needed } => assert_eq!(needed, 2),
+            r => panic!("Expected incomplete buffer: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let invalid: [&[u8]; 3] = [
+            &[0x00, 0x02, 0x00, 0x01],
+            &[0x00, 0x04, 0x00, 0x01, 0x09, 0x00],
+            &[0x00, 0x08, 0x00, 0x01, 0x01, 0x00, 0x03, 0x00, 0x50, 0x01],
+        ];
+        for buffer in invalid {
+            assert!(matches!(
+                MuxFrame::parse(buffer),
+                BufferParseResult::Error(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_form_roundtrip() {
+        let frame = MuxFrame {
+            session_id: 3,
+            status: MuxSessionStatus::New,
+            error: false,
+            target: Some(MuxTarget {
+                network: MuxNetwork::Udp,
+                address: ProxyAddressWithPort {
+                    address: ProxyAddress::IPv4(Ipv4Addr::new(8, 8, 8, 8)),
+                    port: 53,
+                },
+            }),
+            global_id: Some([1, 2, 3, 4, 5, 6, 7, 8]),
+            data: Some(b"query"),
+        };
+        let mut buffer = vec![0; frame.size()];
+        assert_eq!(frame.form(&mut buffer), Ok(buffer.len()));
+        match MuxFrame::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(size, buffer.len());
+                assert_eq!(value.session_id, 3);
+                assert_eq!(value.target.unwrap().network, MuxNetwork::Udp);
+                assert_eq!(value.global_id, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+                assert_eq!(value.data, Some(&b"query"[..]));
+            }
+            r => panic!("Failed to parse formed frame: {:?}", r),
+        }
+    }
+}
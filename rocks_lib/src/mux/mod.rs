@@ -0,0 +1,479 @@
+// Mux.Cool, the multiplexing protocol v2ray and Xray clients speak over a
+// single proxied connection when `mux` is enabled. The client opens
+// sub-connections with New frames, carries their data in Keep frames and
+// closes them with End frames; the server answers with Keep and End frames
+// for the same session ids. UDP sub-connections are full-cone, see `xudp`.
+
+mod frame;
+mod xudp;
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use anyhow::{anyhow, Error};
+pub use frame::*;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    select,
+    sync::mpsc::{self, error::TrySendError},
+    task::{AbortHandle, JoinSet},
+};
+use tracing::info;
+
+use crate::{
+    proxy_protocol::{connect_relayed, Relay},
+    BufferFormer, BufferParseResult, BufferParser, ProxyTarget,
+};
+
+const SESSION_BUFFER: usize = 16;
+
+fn frame_bytes(frame: &MuxFrame) -> Vec<u8> {
+    let mut buffer = vec![0; frame.size()];
+    frame
+        .form(&mut buffer)
+        .expect("buffer is sized for the frame");
+    buffer
+}
+
+fn keep_frame(session_id: u16, data: &[u8]) -> Vec<u8> {
+    frame_bytes(&MuxFrame {
+        session_id,
+        status: MuxSessionStatus::Keep,
+        error: false,
+        target: None,
+        global_id: None,
+        data: Some(data),
+    })
+}
+
+fn end_frame(session_id: u16, error: bool) -> Vec<u8> {
+    frame_bytes(&MuxFrame {
+        session_id,
+        status: MuxSessionStatus::End,
+        error,
+        target: None,
+        global_id: None,
+        data: None,
+    })
+}
+
+/// Data sent by the client on a sub-connection.
+struct MuxPacket {
+    data: Vec<u8>,
+    /// Where a UDP packet goes, when it differs from the session target.
+    target: Option<ProxyTarget>,
+}
+
+/// A sub-connection as the frame reader sees it.
+struct Session {
+    uplink: mpsc::Sender<MuxPacket>,
+    task: AbortHandle,
+}
+
+/// How a sub-connection finished.
+enum SessionEnd {
+    /// The client sent End, it already forgot about the session.
+    ByClient,
+    /// The target closed the connection.
+    ByTarget,
+    /// Another mux connection took over the XUDP session.
+    Detached,
+}
+
+async fn run_tcp_session(
+    session_id: u16,
+    target: ProxyTarget,
+    mut uplink: mpsc::Receiver<MuxPacket>,
+    downlink: mpsc::Sender<Vec<u8>>,
+    relay: Option<Relay>,
+) -> Result<SessionEnd, Error> {
+    let host = *target
+        .lookup_host()
+        .await?
+        .first()
+        .ok_or_else(|| anyhow!("No address found for {}", target))?;
+    let (mut out_rd, mut out_wr) = connect_relayed(host, relay.as_ref()).await?.into_split();
+    let mut buf = vec![0; 8192];
+
+    loop {
+        select! {
+            msg = uplink.recv() => match msg {
+                Some(packet) => out_wr.write_all(&packet.data).await?,
+                None => return Ok(SessionEnd::ByClient),
+            },
+            n = out_rd.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(SessionEnd::ByTarget);
+                }
+                downlink.send(keep_frame(session_id, &buf[..n])).await?;
+            },
+        }
+    }
+}
+
+async fn run_session(
+    session_id: u16,
+    network: MuxNetwork,
+    target: ProxyTarget,
+    global_id: Option<[u8; 8]>,
+    uplink: mpsc::Receiver<MuxPacket>,
+    downlink: mpsc::Sender<Vec<u8>>,
+    relay: Option<Relay>,
+) {
+    let result = match network {
+        MuxNetwork::Tcp => {
+            run_tcp_session(session_id, target.clone(), uplink, downlink.clone(), relay).await
+        }
+        MuxNetwork::Udp => {
+            xudp::run_udp_session(
+                session_id,
+                target.clone(),
+                global_id,
+                uplink,
+                downlink.clone(),
+            )
+            .await
+        }
+    };
+    let end = match result {
+        Ok(SessionEnd::ByClient) => None,
+        Ok(SessionEnd::ByTarget | SessionEnd::Detached) => Some(end_frame(session_id, false)),
+        Err(e) => {
+            info!("mux session {} to {} failed: {:?}", session_id, target, e);
+            Some(end_frame(session_id, true))
+        }
+    };
+    if let Some(end) = end {
+        downlink.send(end).await.ok();
+    }
+}
+
+/// Serve a Mux.Cool connection until the client disconnects.
+/// `initial` holds bytes already read from `in_rd` after the request header.
+/// TCP targets `relay` names are told about the client in a PROXY header.
+pub(crate) async fn serve_mux(
+    mut in_rd: impl AsyncRead + Unpin,
+    mut in_wr: impl AsyncWrite + Unpin,
+    initial: &[u8],
+    remote_addr: SocketAddr,
+    relay: Option<Relay>,
+) -> Result<(), Error> {
+    let (downlink, mut frames) = mpsc::channel::<Vec<u8>>(SESSION_BUFFER);
+    let writer = async {
+        while let Some(frame) = frames.recv().await {
+            in_wr.write_all(&frame).await?;
+            in_wr.flush().await?;
+        }
+        Ok::<_, Error>(())
+    };
+
+    let reader = async {
+        let mut sessions: HashMap<u16, Session> = HashMap::new();
+        let mut tasks = JoinSet::new();
+        let mut pending = initial.to_vec();
+        let mut buf = vec![0; 8192];
+
+        loop {
+            while tasks.try_join_next().is_some() {}
+            let mut consumed = 0;
+            loop {
+                let frame = match MuxFrame::parse(&pending[consumed..]) {
+                    BufferParseResult::Parsed { value, size } => {
+                        consumed += size;
+                        value
+                    }
+                    BufferParseResult::Incomplete { .. } => break,
+                    BufferParseResult::Error(e) => Err(e)?,
+                };
+                let id = frame.session_id;
+                match frame.status {
+                    MuxSessionStatus::New => {
+                        let Some(target) = frame.target else {
+                            continue;
+                        };
+                        let (tx, rx) = mpsc::channel(SESSION_BUFFER);
+                        let address = ProxyTarget::from(&target.address);
+                        info!(
+                            "{} -> mux {} {:?} {}",
+                            remote_addr, id, target.network, address
+                        );
+                        // An all-zero global id means the client does not want XUDP.
+                        let global_id = frame.global_id.filter(|id| *id != [0; 8]);
+                        let task = tasks.spawn(run_session(
+                            id,
+                            target.network,
+                            address,
+                            global_id,
+                            rx,
+                            downlink.clone(),
+                            relay.clone(),
+                        ));
+                        if let Some(data) = frame.data.filter(|d| !d.is_empty()) {
+                            let packet = MuxPacket {
+                                data: data.to_vec(),
+                                target: None,
+                            };
+                            tx.try_send(packet).ok();
+                        }
+                        sessions.insert(id, Session { uplink: tx, task });
+                    }
+                    MuxSessionStatus::Keep => {
+                        let Some(data) = frame.data else {
+                            continue;
+                        };
+                        let packet = MuxPacket {
+                            data: data.to_vec(),
+                            target: frame
+                                .target
+                                .filter(|t| t.network == MuxNetwork::Udp)
+                                .map(|t| ProxyTarget::from(&t.address)),
+                        };
+                        let sent = match sessions.get(&id) {
+                            Some(session) => session.uplink.try_send(packet),
+                            None => Err(TrySendError::Closed(packet)),
+                        };
+                        match sent {
+                            Ok(()) => {}
+                            // Waiting for a session whose target does not
+                            // take its data would hold up all the others.
+                            Err(TrySendError::Full(_)) => {
+                                info!("mux session {} is not keeping up, closing it", id);
+                                if let Some(session) = sessions.remove(&id) {
+                                    session.task.abort();
+                                }
+                                downlink.send(end_frame(id, true)).await?;
+                            }
+                            Err(TrySendError::Closed(_)) => {
+                                sessions.remove(&id);
+                                downlink.send(end_frame(id, false)).await?;
+                            }
+                        }
+                    }
+                    MuxSessionStatus::End => {
+                        sessions.remove(&id);
+                    }
+                    MuxSessionStatus::KeepAlive => {}
+                }
+            }
+            pending.drain(..consumed);
+
+            let n = in_rd.read(&mut buf).await?;
+            if n == 0 {
+                info!("mux shutdown from in ({} sessions open)", sessions.len());
+                tasks.abort_all();
+                return Ok::<_, Error>(());
+            }
+            pending.extend_from_slice(&buf[..n]);
+        }
+    };
+
+    select! {
+        r = reader => r,
+        r = writer => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProxyAddress, ProxyAddressWithPort};
+    use std::net::Ipv4Addr;
+    use tokio::net::{TcpListener, UdpSocket};
+
+    async fn read_frame(rd: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> Vec<u8> {
+        loop {
+            if let BufferParseResult::Parsed { size, .. } = MuxFrame::parse(buf) {
+                return buf.drain(..size).collect();
+            }
+            let mut chunk = [0; 1024];
+            let n = rd.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "unexpected end of mux stream");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mux_tcp_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            stream.write_all(b"world").await.unwrap();
+        });
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_rd, server_wr) = tokio::io::split(server);
+        tokio::spawn(serve_mux(
+            server_rd,
+            server_wr,
+            &[],
+            "127.0.0.1:1".parse().unwrap(),
+            None,
+        ));
+
+        let (mut client_rd, mut client_wr) = tokio::io::split(client);
+        let new = frame_bytes(&MuxFrame {
+            session_id: 9,
+            status: MuxSessionStatus::New,
+            error: false,
+            target: Some(MuxTarget {
+                network: MuxNetwork::Tcp,
+                address: ProxyAddressWithPort {
+                    address: ProxyAddress::IPv4(Ipv4Addr::LOCALHOST),
+                    port,
+                },
+            }),
+            global_id: None,
+            data: Some(b"hel"),
+        });
+        client_wr.write_all(&new).await.unwrap();
+        client_wr.write_all(&keep_frame(9, b"lo")).await.unwrap();
+
+        let mut buf = vec![];
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(frame, keep_frame(9, b"world"));
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(frame, end_frame(9, false));
+
+        client_wr.write_all(&keep_frame(4, b"?")).await.unwrap();
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(frame, end_frame(4, false));
+    }
+
+    #[tokio::test]
+    async fn test_mux_stuck_session() {
+        // The target takes the connection but never reads from it.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let (client, server) = tokio::io::duplex(65536);
+        let (server_rd, server_wr) = tokio::io::split(server);
+        tokio::spawn(serve_mux(
+            server_rd,
+            server_wr,
+            &[],
+            "127.0.0.1:1".parse().unwrap(),
+            None,
+        ));
+
+        let (mut client_rd, mut client_wr) = tokio::io::split(client);
+        let new = frame_bytes(&MuxFrame {
+            session_id: 3,
+            status: MuxSessionStatus::New,
+            error: false,
+            target: Some(MuxTarget {
+                network: MuxNetwork::Tcp,
+                address: ProxyAddressWithPort {
+                    address: ProxyAddress::IPv4(Ipv4Addr::LOCALHOST),
+                    port,
+                },
+            }),
+            global_id: None,
+            data: None,
+        });
+        client_wr.write_all(&new).await.unwrap();
+        tokio::spawn(async move {
+            let keep = keep_frame(3, &[0; 32768]);
+            while client_wr.write_all(&keep).await.is_ok() {}
+        });
+
+        // The session is closed rather than the whole connection stalled.
+        let mut buf = vec![];
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(frame, end_frame(3, true));
+    }
+
+    async fn udp_echo() -> SocketAddr {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn udp_frame(
+        status: MuxSessionStatus,
+        to: SocketAddr,
+        global_id: Option<[u8; 8]>,
+        data: &[u8],
+    ) -> Vec<u8> {
+        frame_bytes(&MuxFrame {
+            session_id: 0,
+            status,
+            error: false,
+            target: Some(MuxTarget {
+                network: MuxNetwork::Udp,
+                address: ProxyAddressWithPort::from(to),
+            }),
+            global_id,
+            data: Some(data),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_mux_xudp_session() {
+        let first = udp_echo().await;
+        let second = udp_echo().await;
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_rd, server_wr) = tokio::io::split(server);
+        tokio::spawn(serve_mux(
+            server_rd,
+            server_wr,
+            &[],
+            "127.0.0.1:1".parse().unwrap(),
+            None,
+        ));
+
+        let (mut client_rd, mut client_wr) = tokio::io::split(client);
+        let new = udp_frame(MuxSessionStatus::New, first, Some(*b"rocks-t2"), b"one");
+        client_wr.write_all(&new).await.unwrap();
+        let mut buf = vec![];
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(
+            frame,
+            udp_frame(MuxSessionStatus::Keep, first, None, b"one")
+        );
+
+        let keep = udp_frame(MuxSessionStatus::Keep, second, None, b"two");
+        client_wr.write_all(&keep).await.unwrap();
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(
+            frame,
+            udp_frame(MuxSessionStatus::Keep, second, None, b"two")
+        );
+
+        // A second connection with the same global id takes the session over.
+        let (other, server) = tokio::io::duplex(4096);
+        let (server_rd, server_wr) = tokio::io::split(server);
+        tokio::spawn(serve_mux(
+            server_rd,
+            server_wr,
+            &[],
+            "127.0.0.1:2".parse().unwrap(),
+            None,
+        ));
+        let (mut other_rd, mut other_wr) = tokio::io::split(other);
+        other_wr.write_all(&new).await.unwrap();
+        let frame = read_frame(&mut client_rd, &mut buf).await;
+        assert_eq!(frame, end_frame(0, false));
+        let mut other_buf = vec![];
+        let frame = read_frame(&mut other_rd, &mut other_buf).await;
+        assert_eq!(
+            frame,
+            udp_frame(MuxSessionStatus::Keep, first, None, b"one")
+        );
+    }
+}
@@ -0,0 +1,267 @@
+// UDP sub-connections of a Mux.Cool session, including XUDP.
+//
+// Every Keep frame may name its own destination and every reply is framed
+// with the address it came from, so one client socket can talk to any number
+// of peers through a single outbound socket (full-cone NAT). When the New
+// frame carries a non-zero global id the outbound socket outlives the mux
+// connection for `XUDP_EXPIRE`, and a client reconnecting with the same id
+// picks it up again, keeping its public port.
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use tokio::{net::UdpSocket, select, sync::mpsc, sync::oneshot};
+use tracing::info;
+
+use super::{
+    frame_bytes, MuxFrame, MuxNetwork, MuxPacket, MuxSessionStatus, MuxTarget, SessionEnd,
+    MAX_FRAME_DATA,
+};
+use crate::{ProxyAddressWithPort, ProxyTarget};
+
+/// How long an outbound socket is kept for a detached XUDP session.
+pub const XUDP_EXPIRE: Duration = Duration::from_secs(60);
+
+struct XudpEntry {
+    socket: Arc<UdpSocket>,
+    generation: u64,
+    detach: Option<oneshot::Sender<()>>,
+}
+
+static XUDP_SESSIONS: LazyLock<Mutex<HashMap<[u8; 8], XudpEntry>>> =
+    LazyLock::new(Default::default);
+static XUDP_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Bind a socket able to reach both IPv4 and IPv6 peers where possible.
+fn bind_full_cone() -> Result<UdpSocket, std::io::Error> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .or_else(|_| std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Take over the outbound socket of `global_id`, detaching its current owner.
+fn attach(global_id: [u8; 8]) -> Result<(Arc<UdpSocket>, u64, oneshot::Receiver<()>), Error> {
+    let generation = XUDP_GENERATION.fetch_add(1, Ordering::Relaxed);
+    let (detach, detached) = oneshot::channel();
+    let mut sessions = XUDP_SESSIONS.lock().unwrap();
+    if let Some(entry) = sessions.get_mut(&global_id) {
+        if let Some(previous) = entry.detach.take() {
+            previous.send(()).ok();
+        }
+        entry.generation = generation;
+        entry.detach = Some(detach);
+        return Ok((entry.socket.clone(), generation, detached));
+    }
+    let socket = Arc::new(bind_full_cone()?);
+    sessions.insert(
+        global_id,
+        XudpEntry {
+            socket: socket.clone(),
+            generation,
+            detach: Some(detach),
+        },
+    );
+    Ok((socket, generation, detached))
+}
+
+/// Let go of the outbound socket of `global_id`, dropping it after
+/// `XUDP_EXPIRE` unless another session attaches in the meantime.
+fn release(global_id: [u8; 8], generation: u64) {
+    let still_owner = move |sessions: &HashMap<[u8; 8], XudpEntry>| {
+        sessions
+            .get(&global_id)
+            .is_some_and(|e| e.generation == generation)
+    };
+    {
+        let mut sessions = XUDP_SESSIONS.lock().unwrap();
+        if !still_owner(&sessions) {
+            return;
+        }
+        sessions.get_mut(&global_id).unwrap().detach = None;
+    }
+    // Nothing left to expire the entry once the runtime is shutting down.
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    runtime.spawn(async move {
+        tokio::time::sleep(XUDP_EXPIRE).await;
+        let mut sessions = XUDP_SESSIONS.lock().unwrap();
+        if still_owner(&sessions) {
+            sessions.remove(&global_id);
+        }
+    });
+}
+
+/// Map `addr` to an address `socket` can send to.
+fn outbound_addr(socket_addr: &SocketAddr, addr: SocketAddr) -> Option<SocketAddr> {
+    match (socket_addr, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            Some((v4.ip().to_ipv6_mapped(), v4.port()).into())
+        }
+        (SocketAddr::V4(_), SocketAddr::V6(v6)) => {
+            Some((v6.ip().to_ipv4_mapped()?, v6.port()).into())
+        }
+        _ => Some(addr),
+    }
+}
+
+/// Undo the IPv4-in-IPv6 mapping of a dual-stack socket.
+fn peer_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => (v4, v6.port()).into(),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+fn packet_frame(session_id: u16, from: SocketAddr, data: &[u8]) -> Vec<u8> {
+    frame_bytes(&MuxFrame {
+        session_id,
+        status: MuxSessionStatus::Keep,
+        error: false,
+        target: Some(MuxTarget {
+            network: MuxNetwork::Udp,
+            address: ProxyAddressWithPort::from(from),
+        }),
+        global_id: None,
+        data: Some(data),
+    })
+}
+
+pub(super) async fn run_udp_session(
+    session_id: u16,
+    target: ProxyTarget,
+    global_id: Option<[u8; 8]>,
+    mut uplink: mpsc::Receiver<MuxPacket>,
+    downlink: mpsc::Sender<Vec<u8>>,
+) -> Result<SessionEnd, Error> {
+    let (socket, mut detached) = match global_id {
+        Some(global_id) => {
+            let (socket, generation, detached) = attach(global_id)?;
+            info!(
+                "xudp {:02x?} attached to {}",
+                global_id,
+                socket.local_addr()?
+            );
+            let release = ReleaseOnDrop(global_id, generation);
+            (socket, Some((detached, release)))
+        }
+        None => (Arc::new(bind_full_cone()?), None),
+    };
+    let local_addr = socket.local_addr()?;
+    let mut resolved: HashMap<ProxyTarget, SocketAddr> = HashMap::new();
+    let mut buf = vec![0; MAX_FRAME_DATA];
+
+    loop {
+        let detach = async {
+            match detached.as_mut() {
+                Some((detached, _)) => detached.await.ok(),
+                None => std::future::pending().await,
+            }
+        };
+        select! {
+            msg = uplink.recv() => {
+                let Some(packet) = msg else {
+                    return Ok(SessionEnd::ByClient);
+                };
+                let target = packet.target.unwrap_or_else(|| target.clone());
+                let addr = match resolved.get(&target) {
+                    Some(addr) => *addr,
+                    None => {
+                        let addr = *target
+                            .lookup_host()
+                            .await?
+                            .first()
+                            .ok_or_else(|| anyhow!("No address found for {}", target))?;
+                        resolved.insert(target, addr);
+                        addr
+                    }
+                };
+                match outbound_addr(&local_addr, addr) {
+                    Some(addr) => {
+                        socket.send_to(&packet.data, addr).await?;
+                    }
+                    None => info!("xudp session {} cannot reach {}", session_id, addr),
+                }
+            },
+            r = socket.recv_from(&mut buf) => {
+                let (n, from) = match r {
+                    Ok(r) => r,
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => continue,
+                    Err(e) => Err(e)?,
+                };
+                downlink.send(packet_frame(session_id, peer_addr(from), &buf[..n])).await?;
+            },
+            Some(()) = detach => {
+                info!("xudp session {} taken over by another connection", session_id);
+                return Ok(SessionEnd::Detached);
+            }
+        }
+    }
+}
+
+/// Releases an XUDP socket however its session task ends, aborts included.
+struct ReleaseOnDrop([u8; 8], u64);
+
+impl Drop for ReleaseOnDrop {
+    fn drop(&mut self) {
+        release(self.0, self.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbound_addr() {
+        let v6: SocketAddr = "[::]:1000".parse().unwrap();
+        let v4: SocketAddr = "0.0.0.0:1000".parse().unwrap();
+        let target_v4: SocketAddr = "1.2.3.4:53".parse().unwrap();
+        let target_v6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        assert_eq!(
+            outbound_addr(&v6, target_v4),
+            Some("[::ffff:1.2.3.4]:53".parse().unwrap())
+        );
+        assert_eq!(outbound_addr(&v6, target_v6), Some(target_v6));
+        assert_eq!(outbound_addr(&v4, target_v4), Some(target_v4));
+        assert_eq!(outbound_addr(&v4, target_v6), None);
+        assert_eq!(peer_addr("[::ffff:1.2.3.4]:53".parse().unwrap()), target_v4);
+        assert_eq!(peer_addr(target_v6), target_v6);
+    }
+
+    #[tokio::test]
+    async fn test_attach_reuses_socket() {
+        let global_id = *b"rocks-t1";
+        let (first, first_gen, mut first_detached) = attach(global_id).unwrap();
+        let (second, second_gen, _second_detached) = attach(global_id).unwrap();
+        assert_ne!(first_gen, second_gen);
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+        assert!(first_detached.try_recv().is_ok());
+
+        // A stale owner letting go must not drop the socket from under the new one.
+        release(global_id, first_gen);
+        assert!(XUDP_SESSIONS
+            .lock()
+            .unwrap()
+            .get(&global_id)
+            .is_some_and(|e| e.detach.is_some()));
+        release(global_id, second_gen);
+        assert!(XUDP_SESSIONS
+            .lock()
+            .unwrap()
+            .get(&global_id)
+            .is_some_and(|e| e.detach.is_none()));
+    }
+}
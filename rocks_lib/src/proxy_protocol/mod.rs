@@ -0,0 +1,765 @@
+// The HAProxy PROXY protocol, which load balancers put in front of a relayed
+// connection to tell the server who the client is.
+//
+// Version 1 is a line of text such as
+// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`. Version 2 is binary: a
+// 12 byte signature, the version and command, the address family and
+// transport, the length of the rest, the addresses and then any number of
+// type-length-value extensions (TLVs).
+//
+// Headers are read from load balancers in front of inbounds and, the other
+// way round, sent to fallbacks and targets that want to know the client.
+//
+// See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::debug;
+
+use crate::{
+    BufferFormer, BufferParseResult, BufferParser, InsufficientBuffer, OutboundProxyProtocolConfig,
+    ProxyProtocolConfig,
+};
+
+pub const V1_PREFIX: &[u8] = b"PROXY ";
+pub const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a version 1 header may be, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_HEADER_LEN: usize = 16;
+const V2_UNIX_ADDRESS_LEN: usize = 216;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ProxyHeaderParseError {
+    #[error("Not a PROXY protocol header")]
+    NotProxyProtocol,
+    #[error("Invalid version 1 header")]
+    InvalidV1,
+    #[error("Unsupported PROXY protocol version or command {0:#04x}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid address family {0:#04x}")]
+    InvalidFamily(u8),
+    #[error("Invalid TLV")]
+    InvalidTlv,
+}
+
+/// A PROXY protocol version, `1` or `2` in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum ProxyVersion {
+    V1,
+    V2,
+}
+
+impl TryFrom<u8> for ProxyVersion {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(format!("unknown PROXY protocol version {}", version)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyCommand {
+    /// The connection was made by the proxy itself, health checks for
+    /// instance, and carries no client address.
+    Local,
+    /// The connection is relayed for a client.
+    Proxy,
+}
+
+/// A type-length-value extension of a version 2 header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyTlv<'a> {
+    pub kind: u8,
+    pub value: &'a [u8],
+}
+
+impl ProxyTlv<'_> {
+    pub const ALPN: u8 = 0x01;
+    pub const AUTHORITY: u8 = 0x02;
+    pub const CRC32C: u8 = 0x03;
+    pub const NOOP: u8 = 0x04;
+    pub const UNIQUE_ID: u8 = 0x05;
+    pub const SSL: u8 = 0x20;
+    pub const NETNS: u8 = 0x30;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyHeader<'a> {
+    pub version: ProxyVersion,
+    pub command: ProxyCommand,
+    /// Whether the relayed connection is UDP rather than TCP.
+    pub datagram: bool,
+    /// The source and destination of the relayed connection, when known.
+    pub addresses: Option<(SocketAddr, SocketAddr)>,
+    pub tlvs: Vec<ProxyTlv<'a>>,
+}
+
+impl ProxyHeader<'_> {
+    /// A header for a TCP connection relayed from `source` to `destination`.
+    /// Mixed address families are both given as IPv6, which is all the
+    /// protocol can carry.
+    pub fn relayed(version: ProxyVersion, source: SocketAddr, destination: SocketAddr) -> Self {
+        let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let (mut source, mut destination) = (canonical(source), canonical(destination));
+        if source.is_ipv4() != destination.is_ipv4() {
+            for addr in [&mut source, &mut destination] {
+                if let IpAddr::V4(ip) = addr.ip() {
+                    addr.set_ip(ip.to_ipv6_mapped().into());
+                }
+            }
+        }
+        Self {
+            version,
+            command: ProxyCommand::Proxy,
+            datagram: false,
+            addresses: Some((source, destination)),
+            tlvs: vec![],
+        }
+    }
+
+    fn v1_line(&self) -> String {
+        match self.addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            ),
+            None => "PROXY UNKNOWN\r\n".to_string(),
+        }
+    }
+
+    fn v2_address_len(&self) -> usize {
+        match self.addresses {
+            Some((SocketAddr::V4(_), _)) => 12,
+            Some((SocketAddr::V6(_), _)) => 36,
+            None => 0,
+        }
+    }
+
+    /// The client address, if the header tells it.
+    pub fn source(&self) -> Option<SocketAddr> {
+        match self.command {
+            ProxyCommand::Local => None,
+            ProxyCommand::Proxy => self.addresses.map(|(source, _)| source),
+        }
+    }
+}
+
+fn parse_v1(buffer: &[u8]) -> BufferParseResult<ProxyHeader<'_>, ProxyHeaderParseError> {
+    let Some(end) = buffer.windows(2).position(|w| w == b"\r\n") else {
+        if buffer.len() >= V1_MAX_LEN {
+            return BufferParseResult::Error(ProxyHeaderParseError::InvalidV1);
+        }
+        return BufferParseResult::Incomplete { needed: 1 };
+    };
+    if end + 2 > V1_MAX_LEN {
+        return BufferParseResult::Error(ProxyHeaderParseError::InvalidV1);
+    }
+    let parse = || {
+        let line = std::str::from_utf8(&buffer[V1_PREFIX.len()..end]).ok()?;
+        let mut fields = line.split(' ');
+        let family = fields.next()?;
+        if family == "UNKNOWN" {
+            return Some(None);
+        }
+        let [source, destination, source_port, destination_port] = [
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+        ];
+        if fields.next().is_some() {
+            return None;
+        }
+        let (source, destination): (IpAddr, IpAddr) = match family {
+            "TCP4" => (
+                source.parse::<Ipv4Addr>().ok()?.into(),
+                destination.parse::<Ipv4Addr>().ok()?.into(),
+            ),
+            "TCP6" => (
+                source.parse::<Ipv6Addr>().ok()?.into(),
+                destination.parse::<Ipv6Addr>().ok()?.into(),
+            ),
+            _ => return None,
+        };
+        Some(Some((
+            SocketAddr::new(source, source_port.parse().ok()?),
+            SocketAddr::new(destination, destination_port.parse().ok()?),
+        )))
+    };
+    match parse() {
+        Some(addresses) => BufferParseResult::Parsed {
+            value: ProxyHeader {
+                version: ProxyVersion::V1,
+                command: ProxyCommand::Proxy,
+                datagram: false,
+                addresses,
+                tlvs: vec![],
+            },
+            size: end + 2,
+        },
+        None => BufferParseResult::Error(ProxyHeaderParseError::InvalidV1),
+    }
+}
+
+fn parse_tlvs(mut buffer: &[u8]) -> Option<Vec<ProxyTlv<'_>>> {
+    let mut tlvs = vec![];
+    while !buffer.is_empty() {
+        let header = buffer.get(..3)?;
+        let len = u16::from_be_bytes([header[1], header[2]]) as usize;
+        tlvs.push(ProxyTlv {
+            kind: header[0],
+            value: buffer.get(3..3 + len)?,
+        });
+        buffer = &buffer[3 + len..];
+    }
+    Some(tlvs)
+}
+
+fn parse_v2(buffer: &[u8]) -> BufferParseResult<ProxyHeader<'_>, ProxyHeaderParseError> {
+    if buffer.len() < V2_HEADER_LEN {
+        return BufferParseResult::Incomplete {
+            needed: V2_HEADER_LEN - buffer.len(),
+        };
+    }
+    let command = match buffer[12] {
+        0x20 => ProxyCommand::Local,
+        0x21 => ProxyCommand::Proxy,
+        other => return BufferParseResult::Error(ProxyHeaderParseError::UnsupportedVersion(other)),
+    };
+    let size = V2_HEADER_LEN + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+    if buffer.len() < size {
+        return BufferParseResult::Incomplete {
+            needed: size - buffer.len(),
+        };
+    }
+    let body = &buffer[V2_HEADER_LEN..size];
+    let family = buffer[13];
+    let datagram = match family & 0x0f {
+        0x00 | 0x01 => false,
+        0x02 => true,
+        _ => return BufferParseResult::Error(ProxyHeaderParseError::InvalidFamily(family)),
+    };
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    let (addresses, address_len) = match family >> 4 {
+        0x0 => (None, 0),
+        0x1 if body.len() >= 12 => {
+            let source = Ipv4Addr::from(<[u8; 4]>::try_from(&body[0..4]).unwrap());
+            let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&body[4..8]).unwrap());
+            let addresses = (
+                SocketAddr::new(source.into(), port(&body[8..10])),
+                SocketAddr::new(destination.into(), port(&body[10..12])),
+            );
+            (Some(addresses), 12)
+        }
+        0x2 if body.len() >= 36 => {
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16]).unwrap());
+            let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&body[16..32]).unwrap());
+            let addresses = (
+                SocketAddr::new(source.into(), port(&body[32..34])),
+                SocketAddr::new(destination.into(), port(&body[34..36])),
+            );
+            (Some(addresses), 36)
+        }
+        // Unix socket paths have no `SocketAddr` to put them in.
+        0x3 if body.len() >= V2_UNIX_ADDRESS_LEN => (None, V2_UNIX_ADDRESS_LEN),
+        _ => return BufferParseResult::Error(ProxyHeaderParseError::InvalidFamily(family)),
+    };
+    let Some(tlvs) = parse_tlvs(&body[address_len..]) else {
+        return BufferParseResult::Error(ProxyHeaderParseError::InvalidTlv);
+    };
+    BufferParseResult::Parsed {
+        value: ProxyHeader {
+            version: ProxyVersion::V2,
+            command,
+            datagram,
+            addresses,
+            tlvs,
+        },
+        size,
+    }
+}
+
+impl<'a> BufferParser<'a> for ProxyHeader<'a> {
+    type Error = ProxyHeaderParseError;
+    type ParseOptions = ();
+
+    /// Parse a version 1 or 2 header. While incomplete, no more is asked for
+    /// than the header needs, so reading exactly `needed` bytes at a time
+    /// never reads past it.
+    fn parse_with_options<'b>(buffer: &'b [u8], _: ()) -> BufferParseResult<Self, Self::Error>
+    where
+        Self: Sized,
+        'b: 'a,
+    {
+        let Some(&first) = buffer.first() else {
+            return BufferParseResult::Incomplete { needed: 1 };
+        };
+        let prefix = if first == V1_PREFIX[0] {
+            V1_PREFIX
+        } else {
+            V2_SIGNATURE
+        };
+        let len = buffer.len().min(prefix.len());
+        if buffer[..len] != prefix[..len] {
+            return BufferParseResult::Error(ProxyHeaderParseError::NotProxyProtocol);
+        }
+        if prefix == V2_SIGNATURE {
+            parse_v2(buffer)
+        } else if len < prefix.len() {
+            BufferParseResult::Incomplete {
+                needed: prefix.len() - len,
+            }
+        } else {
+            parse_v1(buffer)
+        }
+    }
+}
+
+impl BufferFormer for ProxyHeader<'_> {
+    type Error = InsufficientBuffer;
+    type FormingOptions = ();
+
+    fn size_with_option(&self, _: &Self::FormingOptions) -> usize {
+        match self.version {
+            ProxyVersion::V1 => self.v1_line().len(),
+            ProxyVersion::V2 => {
+                V2_HEADER_LEN
+                    + self.v2_address_len()
+                    + self.tlvs.iter().map(|t| 3 + t.value.len()).sum::<usize>()
+            }
+        }
+    }
+
+    fn form_with_option<'a>(
+        &'a self,
+        buffer: &'a mut [u8],
+        _: &Self::FormingOptions,
+    ) -> Result<usize, Self::Error> {
+        let size = self.size();
+        if buffer.len() < size {
+            return Err(InsufficientBuffer);
+        }
+        if self.version == ProxyVersion::V1 {
+            if self
+                .addresses
+                .is_some_and(|(s, d)| s.is_ipv4() != d.is_ipv4())
+            {
+                return Err(InsufficientBuffer);
+            }
+            buffer[..size].copy_from_slice(self.v1_line().as_bytes());
+            return Ok(size);
+        }
+        let len = u16::try_from(size - V2_HEADER_LEN).map_err(|_| InsufficientBuffer)?;
+        buffer[..12].copy_from_slice(V2_SIGNATURE);
+        buffer[12] = match self.command {
+            ProxyCommand::Local => 0x20,
+            ProxyCommand::Proxy => 0x21,
+        };
+        let transport = if self.datagram { 0x02 } else { 0x01 };
+        buffer[13] = match self.addresses {
+            Some((SocketAddr::V4(_), _)) => 0x10 | transport,
+            Some((SocketAddr::V6(_), _)) => 0x20 | transport,
+            None => 0x00,
+        };
+        buffer[14..16].copy_from_slice(&len.to_be_bytes());
+        let mut offset = V2_HEADER_LEN;
+        let mut put = |bytes: &[u8]| {
+            buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+            offset += bytes.len();
+        };
+        match self.addresses {
+            Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+                put(&source.ip().octets());
+                put(&destination.ip().octets());
+                put(&source.port().to_be_bytes());
+                put(&destination.port().to_be_bytes());
+            }
+            Some((SocketAddr::V6(source), SocketAddr::V6(destination))) => {
+                put(&source.ip().octets());
+                put(&destination.ip().octets());
+                put(&source.port().to_be_bytes());
+                put(&destination.port().to_be_bytes());
+            }
+            Some(_) => return Err(InsufficientBuffer),
+            None => {}
+        }
+        for tlv in &self.tlvs {
+            let len = u16::try_from(tlv.value.len()).map_err(|_| InsufficientBuffer)?;
+            put(&[tlv.kind]);
+            put(&len.to_be_bytes());
+            put(tlv.value);
+        }
+        Ok(offset)
+    }
+}
+
+/// Tell `stream`, with a PROXY header of `version`, that it is relayed from
+/// `source` to `destination`.
+pub(crate) async fn send_proxy_header(
+    stream: &mut (impl AsyncWrite + Unpin),
+    version: ProxyVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Result<(), Error> {
+    let header = ProxyHeader::relayed(version, source, destination);
+    let mut buffer = vec![0; header.size()];
+    header
+        .form(&mut buffer)
+        .map_err(|_| anyhow!("Cannot form PROXY header {:?}", header))?;
+    stream.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// PROXY headers for the connections made for `client` to the targets
+/// `config` names.
+#[derive(Debug, Clone)]
+pub(crate) struct Relay {
+    pub(crate) config: Arc<OutboundProxyProtocolConfig>,
+    pub(crate) client: SocketAddr,
+}
+
+/// Connect to `target`. If `relay` names it, the target is first told with a
+/// PROXY header who the connection is for.
+pub(crate) async fn connect_relayed(
+    target: SocketAddr,
+    relay: Option<&Relay>,
+) -> Result<TcpStream, Error> {
+    let mut stream = TcpStream::connect(target).await?;
+    let relay = relay.filter(|r| r.config.to.iter().any(|c| c.contains(&target.ip())));
+    if let Some(relay) = relay {
+        send_proxy_header(&mut stream, relay.config.version, relay.client, target).await?;
+    }
+    Ok(stream)
+}
+
+/// The address of the client behind `peer`. When `config` trusts `peer`, the
+/// connection must start with a PROXY header, which is read off `stream`
+/// without reading past it. Anyone else is taken to be the client.
+pub(crate) async fn resolve_peer(
+    stream: &mut (impl AsyncRead + Unpin),
+    peer: SocketAddr,
+    config: Option<&ProxyProtocolConfig>,
+) -> Result<SocketAddr, Error> {
+    let trusted = config.is_some_and(|c| c.from.iter().any(|cidr| cidr.contains(&peer.ip())));
+    if !trusted {
+        return Ok(peer);
+    }
+    let mut buffer = vec![];
+    loop {
+        match ProxyHeader::parse(&buffer) {
+            BufferParseResult::Parsed { value, .. } => {
+                debug!("PROXY header from {}: {:?}", peer, value);
+                return Ok(value.source().unwrap_or(peer));
+            }
+            BufferParseResult::Incomplete { needed } => {
+                let offset = buffer.len();
+                buffer.resize(offset + needed, 0);
+                stream.read_exact(&mut buffer[offset..]).await?;
+            }
+            BufferParseResult::Error(e) => {
+                return Err(anyhow!("Bad PROXY header from {}: {}", peer, e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IpCidr;
+
+    fn parsed(buffer: &[u8]) -> (ProxyHeader<'_>, usize) {
+        match ProxyHeader::parse(buffer) {
+            BufferParseResult::Parsed { value, size } => (value, size),
+            r => panic!("Failed to parse PROXY header: {:?}", r),
+        }
+    }
+
+    fn error(buffer: &[u8]) -> Option<ProxyHeaderParseError> {
+        match ProxyHeader::parse(buffer) {
+            BufferParseResult::Error(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buffer = V2_SIGNATURE.to_vec();
+        buffer.extend_from_slice(&[ver_cmd, family]);
+        buffer.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(body);
+        buffer
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let buffer = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n\x16\x03";
+        let (header, size) = parsed(buffer);
+        assert_eq!(size, buffer.len() - 2);
+        assert_eq!(header.version, ProxyVersion::V1);
+        assert_eq!(header.source(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.addresses.unwrap().1,
+            "198.51.100.1:443".parse().unwrap()
+        );
+
+        let (header, _) = parsed(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n");
+        assert_eq!(header.source(), Some("[2001:db8::1]:1".parse().unwrap()));
+        let (header, _) = parsed(b"PROXY UNKNOWN whatever\r\n");
+        assert_eq!(header.source(), None);
+
+        let too_long = [V1_PREFIX, &[b'x'; V1_MAX_LEN]].concat();
+        for invalid in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 1 99999\r\n",
+            &too_long,
+        ] {
+            assert_eq!(
+                error(invalid),
+                Some(ProxyHeaderParseError::InvalidV1),
+                "{}",
+                String::from_utf8_lossy(invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        assert!(matches!(
+            ProxyHeader::parse(b""),
+            BufferParseResult::Incomplete { needed: 1 }
+        ));
+        assert!(matches!(
+            ProxyHeader::parse(b"PRO"),
+            BufferParseResult::Incomplete { needed: 3 }
+        ));
+        assert!(matches!(
+            ProxyHeader::parse(b"PROXY TCP4 1.2.3.4"),
+            BufferParseResult::Incomplete { needed: 1 }
+        ));
+        assert!(matches!(
+            ProxyHeader::parse(&V2_SIGNATURE[..5]),
+            BufferParseResult::Incomplete { needed: 11 }
+        ));
+        let header = v2(0x21, 0x11, &[0; 12]);
+        assert!(matches!(
+            ProxyHeader::parse(&header[..20]),
+            BufferParseResult::Incomplete { needed: 8 }
+        ));
+        assert_eq!(
+            error(b"GET / HTTP/1.1\r\n"),
+            Some(ProxyHeaderParseError::NotProxyProtocol)
+        );
+        assert_eq!(
+            error(b"\r\n\r\nX"),
+            Some(ProxyHeaderParseError::NotProxyProtocol)
+        );
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        body.extend_from_slice(&[ProxyTlv::AUTHORITY, 0, 11]);
+        body.extend_from_slice(b"example.com");
+        body.extend_from_slice(&[ProxyTlv::NOOP, 0, 0]);
+        let buffer = v2(0x21, 0x11, &body);
+        let (header, size) = parsed(&buffer);
+        assert_eq!(size, buffer.len());
+        assert_eq!(header.version, ProxyVersion::V2);
+        assert!(!header.datagram);
+        assert_eq!(header.source(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.tlvs,
+            vec![
+                ProxyTlv {
+                    kind: ProxyTlv::AUTHORITY,
+                    value: b"example.com"
+                },
+                ProxyTlv {
+                    kind: ProxyTlv::NOOP,
+                    value: b""
+                },
+            ]
+        );
+
+        let mut body = vec![0; 36];
+        body[15] = 1;
+        body[33] = 53;
+        let buffer = v2(0x21, 0x22, &body);
+        let (header, _) = parsed(&buffer);
+        assert!(header.datagram);
+        assert_eq!(header.source(), Some("[::1]:53".parse().unwrap()));
+
+        let buffer = v2(0x20, 0x11, &[1; 12]);
+        let (header, _) = parsed(&buffer);
+        assert_eq!(header.command, ProxyCommand::Local);
+        assert_eq!(header.source(), None);
+        let buffer = v2(0x21, 0x31, &[0; 216]);
+        assert_eq!(parsed(&buffer).0.source(), None);
+        let buffer = v2(0x21, 0x00, &[]);
+        assert_eq!(parsed(&buffer).0.addresses, None);
+    }
+
+    #[test]
+    fn test_parse_v2_invalid() {
+        assert_eq!(
+            error(&v2(0x11, 0x11, &[0; 12])),
+            Some(ProxyHeaderParseError::UnsupportedVersion(0x11))
+        );
+        assert_eq!(
+            error(&v2(0x21, 0x11, &[0; 8])),
+            Some(ProxyHeaderParseError::InvalidFamily(0x11))
+        );
+        assert_eq!(
+            error(&v2(0x21, 0x41, &[])),
+            Some(ProxyHeaderParseError::InvalidFamily(0x41))
+        );
+        assert_eq!(
+            error(&v2(0x21, 0x11, &[0; 14])),
+            Some(ProxyHeaderParseError::InvalidTlv)
+        );
+    }
+
+    #[test]
+    fn test_form_roundtrip() {
+        let v4 = |s: &str| s.parse::<SocketAddr>().unwrap();
+        for (version, source, destination) in [
+            (ProxyVersion::V1, "192.0.2.1:56324", "198.51.100.1:443"),
+            (ProxyVersion::V1, "[2001:db8::1]:1", "[2001:db8::2]:2"),
+            (ProxyVersion::V2, "192.0.2.1:56324", "198.51.100.1:443"),
+            (ProxyVersion::V2, "[2001:db8::1]:1", "[2001:db8::2]:2"),
+        ] {
+            let header = ProxyHeader::relayed(version, v4(source), v4(destination));
+            let mut buffer = vec![0; header.size()];
+            assert_eq!(header.form(&mut buffer), Ok(buffer.len()));
+            let (parsed, size) = parsed(&buffer);
+            assert_eq!(size, buffer.len());
+            assert_eq!(parsed, header);
+        }
+
+        let header = ProxyHeader::relayed(
+            ProxyVersion::V1,
+            v4("192.0.2.1:56324"),
+            v4("198.51.100.1:443"),
+        );
+        let mut buffer = vec![0; header.size()];
+        header.form(&mut buffer).unwrap();
+        assert_eq!(buffer, b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n");
+        assert_eq!(header.form(&mut [0; 10]), Err(InsufficientBuffer));
+
+        let header = ProxyHeader {
+            tlvs: vec![ProxyTlv {
+                kind: ProxyTlv::AUTHORITY,
+                value: b"example.com",
+            }],
+            ..ProxyHeader::relayed(ProxyVersion::V2, v4("[::1]:1"), v4("[::1]:2"))
+        };
+        let mut buffer = vec![0; header.size()];
+        header.form(&mut buffer).unwrap();
+        assert_eq!(parsed(&buffer).0, header);
+    }
+
+    #[test]
+    fn test_relayed_mixed_families() {
+        let header = ProxyHeader::relayed(
+            ProxyVersion::V1,
+            "192.0.2.1:1".parse().unwrap(),
+            "[2001:db8::2]:2".parse().unwrap(),
+        );
+        assert_eq!(
+            header.source(),
+            Some("[::ffff:192.0.2.1]:1".parse().unwrap())
+        );
+        let header = ProxyHeader::relayed(
+            ProxyVersion::V2,
+            "[::ffff:192.0.2.1]:1".parse().unwrap(),
+            "198.51.100.1:2".parse().unwrap(),
+        );
+        assert_eq!(header.source(), Some("192.0.2.1:1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_connect_relayed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let client: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let cidr = |c: &str| IpCidr::try_from(c.to_string()).unwrap();
+        let relay = |to: &str| Relay {
+            config: Arc::new(OutboundProxyProtocolConfig {
+                version: ProxyVersion::V2,
+                to: vec![cidr(to)],
+            }),
+            client,
+        };
+        let config = ProxyProtocolConfig {
+            from: vec![cidr("127.0.0.1")],
+        };
+
+        // A target in range is told, any other is not.
+        for (to, told) in [("127.0.0.0/8", true), ("10.0.0.0/8", false)] {
+            let mut stream = connect_relayed(target, Some(&relay(to))).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let (mut accepted, peer) = listener.accept().await.unwrap();
+            if told {
+                let resolved = resolve_peer(&mut accepted, peer, Some(&config))
+                    .await
+                    .unwrap();
+                assert_eq!(resolved, client);
+            }
+            let mut buf = [0; 5];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_peer() {
+        let config = ProxyProtocolConfig {
+            from: vec![IpCidr::try_from("10.0.0.0/8".to_string()).unwrap()],
+        };
+        let balancer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let direct: SocketAddr = "192.0.2.9:1000".parse().unwrap();
+
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nrest"[..];
+        let peer = resolve_peer(&mut stream, balancer, Some(&config))
+            .await
+            .unwrap();
+        assert_eq!(peer, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(stream, b"rest");
+
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"[..];
+        let peer = resolve_peer(&mut stream, direct, Some(&config))
+            .await
+            .unwrap();
+        assert_eq!(peer, direct);
+        let mut stream = &b"\x16\x03\x01"[..];
+        assert!(resolve_peer(&mut stream, balancer, Some(&config))
+            .await
+            .is_err());
+        assert_eq!(
+            resolve_peer(&mut stream, balancer, None).await.unwrap(),
+            balancer
+        );
+    }
+}
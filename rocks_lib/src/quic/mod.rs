@@ -0,0 +1,191 @@
+// QUIC inbounds, with quinn. QUIC recovers from loss per stream rather than
+// per connection, which suits lossy mobile links better than the TCP-based
+// transports. Every bidirectional stream a client opens carries a VLESS
+// session of its own, so one connection can serve many sessions.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Error;
+use quinn::{
+    crypto::rustls::{HandshakeData, QuicServerConfig},
+    ConnectionError, Endpoint, Incoming,
+};
+use rustls::ServerConfig;
+use tracing::info;
+
+use crate::{
+    buffer_parser::Protocol,
+    fallback::{FallbackInfo, Fallbacks},
+    OutboundProxyProtocolConfig, VlessProtocol, VlessUsers,
+};
+
+/// A QUIC endpoint listening on `listen`, with `tls` for its handshakes.
+pub(crate) fn server_endpoint(listen: SocketAddr, tls: ServerConfig) -> Result<Endpoint, Error> {
+    let crypto = QuicServerConfig::try_from(tls)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(Endpoint::server(config, listen)?)
+}
+
+/// What the client said in the handshake of `connection`, for fallbacks.
+fn fallback_info(connection: &quinn::Connection, local_addr: SocketAddr) -> FallbackInfo {
+    let handshake = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok());
+    FallbackInfo {
+        sni: handshake.as_ref().and_then(|h| h.server_name.clone()),
+        alpn: handshake
+            .as_ref()
+            .and_then(|h| h.protocol.as_ref())
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        local_addr: Some(local_addr),
+    }
+}
+
+async fn serve_connection(
+    incoming: Incoming,
+    local_addr: SocketAddr,
+    users: Arc<VlessUsers>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    fallbacks: Arc<Fallbacks>,
+) -> Result<(), Error> {
+    let connection = incoming.await?;
+    let addr = connection.remote_address();
+    info!("New QUIC connection from: {}", addr);
+    let proto = VlessProtocol::new(users)
+        .with_fallbacks(fallbacks, fallback_info(&connection, local_addr))
+        .with_outbound_proxy_protocol(outbound_proxy_protocol);
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let proto = proto.clone();
+        tokio::spawn(async move {
+            Protocol::handle(&proto, tokio::io::join(recv, send), addr)
+                .await
+                .unwrap_or_else(|e| info!("Error: {:?}", e));
+        });
+    }
+}
+
+/// Serve VLESS on the streams of every connection `endpoint` accepts.
+pub(crate) async fn serve_quic(
+    endpoint: Endpoint,
+    users: Arc<VlessUsers>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    fallbacks: Fallbacks,
+) -> Result<(), Error> {
+    let local_addr = endpoint.local_addr()?;
+    info!("started listening on {} (QUIC)", local_addr);
+    let fallbacks = Arc::new(fallbacks);
+    while let Some(incoming) = endpoint.accept().await {
+        let users = users.clone();
+        let fallbacks = fallbacks.clone();
+        let outbound_proxy_protocol = outbound_proxy_protocol.clone();
+        tokio::spawn(async move {
+            serve_connection(
+                incoming,
+                local_addr,
+                users,
+                outbound_proxy_protocol,
+                fallbacks,
+            )
+            .await
+            .unwrap_or_else(|e| info!("Error: {:?}", e));
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tls::tests::self_signed, BufferFormer, ProxyAddress, ProxyAddressWithPort, VlessAddons,
+        VlessCommand, VlessRequestHeader, VlessUser, FLOW_NONE,
+    };
+    use quinn::{crypto::rustls::QuicClientConfig, ClientConfig};
+    use rustls::RootCertStore;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    fn client_config(cert: rustls::pki_types::CertificateDer<'static>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"vless".to_vec()];
+        ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_vless_over_quic() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = target.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut rd, mut wr) = stream.split();
+                    tokio::io::copy(&mut rd, &mut wr).await.unwrap();
+                });
+            }
+        });
+
+        let (tls, cert) = self_signed("quic", &["vless"]);
+        let endpoint =
+            server_endpoint("127.0.0.1:0".parse().unwrap(), tls.server_config().unwrap()).unwrap();
+        let server_addr = endpoint.local_addr().unwrap();
+        let user = "alice".parse::<VlessUser>().unwrap();
+        let users = Arc::new(VlessUsers::new([user.clone()]));
+        tokio::spawn(serve_quic(
+            endpoint,
+            users,
+            None,
+            Fallbacks::new(vec![], None),
+        ));
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(client_config(cert));
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        // Two sessions over the same connection, one per stream.
+        for message in [&b"first"[..], b"second"] {
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            let header = VlessRequestHeader {
+                address: ProxyAddressWithPort {
+                    address: ProxyAddress::IPv4(Ipv4Addr::LOCALHOST),
+                    port: target_addr.port(),
+                },
+                user: user.id,
+                addons: VlessAddons {
+                    flow: FLOW_NONE,
+                    seed: &[],
+                },
+                command: VlessCommand::Tcp,
+            };
+            let mut request = vec![0; header.size()];
+            header.form(&mut request).unwrap();
+            request.extend_from_slice(message);
+            send.write_all(&request).await.unwrap();
+
+            // The VLESS response header, then the echo.
+            let mut response = vec![0; 2 + message.len()];
+            recv.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response[..2], [0, 0]);
+            assert_eq!(&response[2..], message);
+            send.finish().unwrap();
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// The static decoy website, served from a directory with warp. It runs on its
+// own listener and is also served in-process on connections handed over by
+// inbounds, so probes see the same site whichever port they hit.
+
+use std::path::PathBuf;
+
+use anyhow::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use warp::{Filter, Rejection, Reply};
+
+use crate::Listener;
+
+/// The routes of the site rooted at `root`.
+pub fn site_routes(
+    root: PathBuf,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    warp::fs::dir(root)
+}
+
+/// Serve the site rooted at `root` on a listener of its own.
+pub async fn run_site(root: PathBuf, listener: Listener) {
+    warp::serve(site_routes(root))
+        .run_incoming(listener.incoming())
+        .await
+}
+
+/// Serve the site over a single, already accepted connection, in HTTP/1 or
+/// HTTP/2 as the client speaks it.
+pub(crate) async fn serve_site_connection(
+    io: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    root: PathBuf,
+) -> Result<(), Error> {
+    // The connection future is not `Sync`, which protocol handlers must be,
+    // so it runs on its own task.
+    let connection =
+        hyper::server::conn::Http::new().serve_connection(io, warp::service(site_routes(root)));
+    tokio::spawn(connection).await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serve_site_connection() {
+        let root = std::env::temp_dir().join(format!("rocks-site-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "<h1>hi</h1>").unwrap();
+        std::fs::write(root.join("a.txt"), "a file").unwrap();
+
+        let get = |path: &str| {
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+                path
+            );
+            let root = root.clone();
+            async move {
+                let (mut client, server) = tokio::io::duplex(4096);
+                tokio::spawn(serve_site_connection(server, root));
+                client.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+        let response = get("/").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("<h1>hi</h1>"), "{}", response);
+        // Not only the index: every file of the site is served.
+        let response = get("/a.txt").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("a file"), "{}", response);
+        let response = get("/missing.txt").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_serve_site_connection_h2() {
+        let root = std::env::temp_dir().join(format!("rocks-site-h2-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "<h1>hi</h1>").unwrap();
+
+        let (client, server) = tokio::io::duplex(65536);
+        tokio::spawn(serve_site_connection(server, root));
+        let (h2, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let mut h2 = h2.ready().await.unwrap();
+        let request = http::Request::get("https://x/").body(()).unwrap();
+        let (response, _) = h2.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let mut body = response.into_body();
+        let mut received = vec![];
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, b"<h1>hi</h1>");
+    }
+}
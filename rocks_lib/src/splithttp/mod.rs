@@ -0,0 +1,390 @@
+// SplitHTTP (XHTTP "packet-up"), Xray's transport for networks that let no
+// upgrade or long-lived upload through. The client downloads with a single
+// streaming GET of `<path>/<session>` and uploads with POSTs to
+// `<path>/<session>/<seq>`, each carrying the next chunk of the stream. POSTs
+// may come over several connections and out of order, so chunks are put back
+// in order by `seq` before VLESS sees them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    body::HttpBody,
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::info;
+
+use crate::{
+    http::RequestHead,
+    websocket::{client_addr, handle_stream_sink},
+    OutboundProxyProtocolConfig, SplitHttpConfig, VlessUsers,
+};
+
+/// How long a session waits for its GET before it is dropped.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages queued for the download before VLESS waits for the client.
+const DOWNLOAD_BUFFER: usize = 16;
+
+/// Puts the chunks of an upload back in order.
+struct Reorder {
+    next_seq: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+    max_pending: usize,
+}
+
+impl Reorder {
+    fn new(max_pending: usize) -> Self {
+        Self {
+            next_seq: 0,
+            pending: BTreeMap::new(),
+            max_pending,
+        }
+    }
+
+    /// Take chunk `seq`, returning every chunk that is now next in order.
+    fn push(&mut self, seq: u64, data: Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
+        if seq < self.next_seq || self.pending.contains_key(&seq) {
+            return Err(anyhow!("Chunk {} sent twice", seq));
+        }
+        if seq - self.next_seq >= self.max_pending as u64 {
+            return Err(anyhow!(
+                "Chunk {} is too far ahead of {}",
+                seq,
+                self.next_seq
+            ));
+        }
+        self.pending.insert(seq, data);
+        let mut ready = vec![];
+        while let Some(data) = self.pending.remove(&self.next_seq) {
+            ready.push(data);
+            self.next_seq += 1;
+        }
+        Ok(ready)
+    }
+}
+
+struct Session {
+    upload: tokio::sync::Mutex<(Reorder, mpsc::Sender<Vec<u8>>)>,
+    /// The uploaded stream, until the GET starting the session takes it.
+    uplink: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+}
+
+/// The SplitHTTP sessions of an inbound, whichever connections their
+/// requests come over.
+pub(crate) struct SplitHttp {
+    config: SplitHttpConfig,
+    /// `config.path`, ending with a slash.
+    prefix: String,
+    users: Arc<VlessUsers>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    /// How many of `sessions` are yet to start downloading.
+    pending: AtomicUsize,
+}
+
+/// Starting a session would leave more waiting for their download than
+/// configured.
+#[derive(Debug, thiserror::Error)]
+#[error("Too many sessions waiting for their download")]
+struct TooManySessions;
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+impl SplitHttp {
+    pub(crate) fn new(
+        config: SplitHttpConfig,
+        users: Arc<VlessUsers>,
+        outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    ) -> Self {
+        let prefix = format!("{}/", config.path.trim_end_matches('/'));
+        Self {
+            config,
+            prefix,
+            users,
+            outbound_proxy_protocol,
+            sessions: Mutex::new(HashMap::new()),
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether requests for `path` belong to SplitHTTP sessions.
+    pub(crate) fn serves(&self, path: &str) -> bool {
+        path.starts_with(&self.prefix)
+    }
+
+    /// The session `id`, started by whichever of its requests comes first.
+    fn session(self: &Arc<Self>, id: &str) -> Result<Arc<Session>, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get(id) {
+            return Ok(session.clone());
+        }
+        if self.pending.load(Ordering::Relaxed) >= self.config.max_pending_sessions {
+            return Err(TooManySessions.into());
+        }
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(self.config.max_buffered_posts);
+        let session = Arc::new(Session {
+            upload: tokio::sync::Mutex::new((Reorder::new(self.config.max_buffered_posts), tx)),
+            uplink: Mutex::new(Some(rx)),
+        });
+        sessions.insert(id.to_string(), session.clone());
+
+        let this = self.clone();
+        let id = id.to_string();
+        let pending = session.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(SESSION_TIMEOUT).await;
+            if pending.uplink.lock().unwrap().take().is_some() {
+                info!("SplitHTTP session {} never started downloading", id);
+                this.pending.fetch_sub(1, Ordering::Relaxed);
+                this.remove(&id, &pending);
+            }
+        });
+        Ok(session)
+    }
+
+    fn remove(&self, id: &str, session: &Arc<Session>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(id).is_some_and(|s| Arc::ptr_eq(s, session)) {
+            sessions.remove(id);
+        }
+    }
+
+    /// Start the download of session `id`, handing the session to VLESS.
+    fn download(
+        self: &Arc<Self>,
+        id: &str,
+        request: &Request<Body>,
+        peer: SocketAddr,
+    ) -> Result<Response<Body>, Error> {
+        let session = self.session(id)?;
+        let uplink = session
+            .uplink
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow!("Session {} is already downloading", id))?;
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        let addr = client_addr(
+            peer,
+            &RequestHead::from_request(request),
+            &self.config.trusted_proxies,
+            &self.config.real_ip_header,
+        );
+        info!("SplitHTTP session {} from {}", id, addr);
+        let (downlink, body) = futures::channel::mpsc::channel::<Vec<u8>>(DOWNLOAD_BUFFER);
+        let stream = ReceiverStream::new(uplink).map(Ok);
+        let sink = downlink.sink_map_err(|e| anyhow!("Download closed: {}", e));
+
+        let this = self.clone();
+        let id = id.to_string();
+        let users = self.users.clone();
+        let outbound_proxy_protocol = self.outbound_proxy_protocol.clone();
+        tokio::spawn(async move {
+            handle_stream_sink(stream, sink, addr, users, outbound_proxy_protocol)
+                .await
+                .unwrap_or_else(|e| info!("Error: {:?}", e));
+            this.remove(&id, &session);
+        });
+
+        Ok(Response::builder()
+            // Keeps nginx and the like from buffering the download.
+            .header("X-Accel-Buffering", "no")
+            .header(CACHE_CONTROL, "no-store")
+            .header(CONTENT_TYPE, "text/event-stream")
+            .body(Body::wrap_stream(body.map(Ok::<_, Infallible>)))?)
+    }
+
+    /// Add chunk `seq` to the upload of session `id`.
+    async fn upload(self: &Arc<Self>, id: &str, seq: &str, mut body: Body) -> Result<(), Error> {
+        let seq: u64 = seq
+            .parse()
+            .map_err(|_| anyhow!("Invalid chunk number {}", seq))?;
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > self.config.max_post_bytes {
+                return Err(anyhow!("Chunk {} of session {} is too large", seq, id));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        let session = self.session(id)?;
+        let mut upload = session.upload.lock().await;
+        let (reorder, uplink) = &mut *upload;
+        for data in reorder.push(seq, data)? {
+            uplink
+                .send(data)
+                .await
+                .map_err(|_| anyhow!("Session {} is over", id))?;
+        }
+        Ok(())
+    }
+
+    async fn respond(self: Arc<Self>, request: Request<Body>, peer: SocketAddr) -> Response<Body> {
+        let path = request.uri().path().to_string();
+        let Some(rest) = path.strip_prefix(&self.prefix) else {
+            return status(StatusCode::NOT_FOUND);
+        };
+        let mut parts = rest.trim_end_matches('/').split('/');
+        let result = match (request.method(), parts.next(), parts.next(), parts.next()) {
+            (&Method::GET, Some(id), None, None) if !id.is_empty() => {
+                self.download(id, &request, peer)
+            }
+            (&Method::POST, Some(id), Some(seq), None) if !id.is_empty() => {
+                let (id, seq) = (id.to_string(), seq.to_string());
+                self.upload(&id, &seq, request.into_body())
+                    .await
+                    .map(|()| status(StatusCode::OK))
+            }
+            _ => return status(StatusCode::NOT_FOUND),
+        };
+        result.unwrap_or_else(|e| {
+            info!("{} from {}: {}", path, peer, e);
+            if e.is::<TooManySessions>() {
+                status(StatusCode::TOO_MANY_REQUESTS)
+            } else {
+                status(StatusCode::BAD_REQUEST)
+            }
+        })
+    }
+
+    /// Serve the requests of a connection from `peer`, over HTTP/2 if `h2`
+    /// or else HTTP/1.1.
+    pub(crate) async fn serve_connection(
+        self: Arc<Self>,
+        io: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        peer: SocketAddr,
+        h2: bool,
+    ) -> Result<(), Error> {
+        let service = service_fn(move |request| {
+            let this = self.clone();
+            async move { Ok::<_, Infallible>(this.respond(request, peer).await) }
+        });
+        let mut http = Http::new();
+        if h2 {
+            http.http2_only(true);
+        } else {
+            http.http1_only(true);
+        }
+        http.serve_connection(io, service).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reorder() {
+        let mut reorder = Reorder::new(3);
+        assert!(reorder.push(1, b"b".to_vec()).unwrap().is_empty());
+        assert!(reorder.push(2, b"c".to_vec()).unwrap().is_empty());
+        assert_eq!(
+            reorder.push(0, b"a".to_vec()).unwrap(),
+            [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        assert!(reorder.push(1, vec![]).is_err());
+        assert!(reorder.push(4, b"e".to_vec()).unwrap().is_empty());
+        assert!(reorder.push(4, vec![]).is_err());
+        // Only `max_pending` chunks may wait for the next one.
+        assert!(reorder.push(6, vec![]).is_err());
+        assert_eq!(reorder.push(3, b"d".to_vec()).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_paths() {
+        let config = SplitHttpConfig {
+            path: "/xhttp/".to_string(),
+            ..SplitHttpConfig::default()
+        };
+        let split_http = Arc::new(SplitHttp::new(config, Arc::new(VlessUsers::new([])), None));
+        assert!(split_http.serves("/xhttp/abc"));
+        assert!(!split_http.serves("/xhttp"));
+        assert!(!split_http.serves("/other/abc"));
+
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let request = |method: Method, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let respond = |request| split_http.clone().respond(request, peer);
+        // Only the start of a VLESS header, so the session stays open.
+        let response = respond(request(Method::POST, "/xhttp/s1/0?x_padding=000", "\0")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = respond(request(Method::POST, "/xhttp/s1/x", "hi")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = respond(request(Method::PUT, "/xhttp/s1", "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = respond(request(Method::GET, "/other/s1", "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let too_large = "x".repeat(SplitHttpConfig::default().max_post_bytes + 1);
+        let response = respond(request(Method::POST, "/xhttp/s2/0", &too_large)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = respond(request(Method::GET, "/xhttp/s1", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Accel-Buffering"], "no");
+        // A session has a single download.
+        let response = respond(request(Method::GET, "/xhttp/s1", "")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_sessions() {
+        let config = SplitHttpConfig {
+            max_pending_sessions: 1,
+            ..SplitHttpConfig::default()
+        };
+        let split_http = Arc::new(SplitHttp::new(config, Arc::new(VlessUsers::new([])), None));
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from("\0"))
+                .unwrap()
+        };
+        let respond = |request| split_http.clone().respond(request, peer);
+        let response = respond(request(Method::POST, "/s1/0")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = respond(request(Method::POST, "/s2/0")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = respond(request(Method::GET, "/s2")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // The session waiting still takes its own requests.
+        let response = respond(request(Method::POST, "/s1/1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Once it is downloading, another may start.
+        let response = respond(request(Method::GET, "/s1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = respond(request(Method::POST, "/s2/0")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
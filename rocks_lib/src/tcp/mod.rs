@@ -28,6 +28,7 @@ pub async fn proxy(
                     return Ok(());
                 }
                 out_wr.write_all(&buf_in[..n]).await?;
+                out_wr.flush().await?;
             },
             n = out_rd.read(&mut buf_out) => {
                 let n = n?;
@@ -38,6 +39,7 @@ pub async fn proxy(
                     return Ok(());
                 }
                 in_wr.write_all(&buf_out[..n]).await?;
+                in_wr.flush().await?;
             },
         }
     }
@@ -0,0 +1,339 @@
+// TLS termination for inbounds, with rustls. An inbound with a `tls` section
+// wraps every accepted connection in a server-side TLS session before any
+// transport or protocol sees it.
+//
+// The session reads the connection one record at a time, so that Vision can
+// switch either direction over to the raw connection right after a record.
+
+mod records;
+
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use anyhow::{anyhow, Context as _, Error};
+use records::Records;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::debug;
+
+use crate::{fallback::FallbackInfo, listener::Connection, TlsConfig};
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("reading certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("reading private key from {}", path.display()))?
+        .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+impl TlsConfig {
+    /// Build the rustls configuration: load the certificate chain and key
+    /// and set the ALPN protocols offered to clients.
+    pub fn server_config(&self) -> Result<ServerConfig, Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+        config.alpn_protocols = self.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Ok(config)
+    }
+
+    pub fn acceptor(&self) -> Result<TlsAcceptor, Error> {
+        Ok(TlsAcceptor::from(Arc::new(self.server_config()?)))
+    }
+}
+
+/// Takes the reads or the writes of a TLS stream off the TLS session and onto
+/// the raw connection under it, for Vision's direct copy.
+#[derive(Debug, Default)]
+pub(crate) struct RawSwitch {
+    reads: AtomicBool,
+    writes: AtomicBool,
+}
+
+impl RawSwitch {
+    /// Read the raw connection once what the session decrypted is read.
+    pub(crate) fn switch_reads(&self) {
+        self.reads.store(true, Ordering::Release);
+    }
+
+    /// Write the raw connection once what the session encrypted is sent.
+    pub(crate) fn switch_writes(&self) {
+        self.writes.store(true, Ordering::Release);
+    }
+}
+
+/// An accepted connection, with or without TLS.
+pub(crate) enum MaybeTlsStream {
+    Plain(Connection),
+    Tls(Box<TlsStream<Records<Connection>>>, Arc<RawSwitch>),
+}
+
+impl MaybeTlsStream {
+    /// Accept `stream`, completing the TLS handshake first if `tls` is set.
+    pub(crate) async fn accept(
+        stream: Connection,
+        tls: Option<&TlsAcceptor>,
+    ) -> Result<Self, Error> {
+        let Some(acceptor) = tls else {
+            return Ok(Self::Plain(stream));
+        };
+        let stream = acceptor.accept(Records::new(stream)).await?;
+        let stream = Self::Tls(Box::new(stream), Arc::default());
+        debug!(
+            "tls handshake done, alpn {:?}",
+            stream.alpn().map(String::from_utf8_lossy)
+        );
+        Ok(stream)
+    }
+
+    /// The protocol the client and server agreed on with ALPN, if any.
+    pub(crate) fn alpn(&self) -> Option<&[u8]> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Tls(stream, _) => stream.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    /// The server name the client asked for with SNI, if any.
+    pub(crate) fn sni(&self) -> Option<&str> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Tls(stream, _) => stream.get_ref().1.server_name(),
+        }
+    }
+
+    /// The address the client connected to.
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Plain(stream) => stream.local_addr(),
+            Self::Tls(stream, _) => stream.get_ref().0.get_ref().local_addr(),
+        }
+    }
+
+    /// The switch to the raw connection, if there is one under TLS.
+    pub(crate) fn raw_switch(&self) -> Option<Arc<RawSwitch>> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Tls(_, switch) => Some(switch.clone()),
+        }
+    }
+
+    pub(crate) fn fallback_info(&self) -> FallbackInfo {
+        FallbackInfo {
+            sni: self.sni().map(String::from),
+            alpn: self.alpn().map(|p| String::from_utf8_lossy(p).into_owned()),
+            local_addr: self.local_addr(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let (stream, switch) = match self.get_mut() {
+            Self::Plain(stream) => return Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream, switch) => (stream, switch),
+        };
+        // What the session decrypted goes first, and only then is another
+        // record read.
+        let (records, session) = stream.get_mut();
+        match session.reader().read(buf.initialize_unfilled()) {
+            Ok(n) if n > 0 => {
+                buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::UnexpectedEof) => {}
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+        if !switch.reads.load(Ordering::Acquire) {
+            records.limit_to_one();
+            return Pin::new(&mut **stream).poll_read(cx, buf);
+        }
+        records.poll_read_raw(cx, buf)
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream, switch) if switch.writes.load(Ordering::Acquire) => {
+                ready!(Pin::new(&mut **stream).poll_flush(cx))?;
+                Pin::new(stream.get_mut().0).poll_write(cx, buf)
+            }
+            Self::Tls(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream, switch) if switch.writes.load(Ordering::Acquire) => {
+                ready!(Pin::new(&mut **stream).poll_flush(cx))?;
+                Pin::new(stream.get_mut().0).poll_shutdown(cx)
+            }
+            Self::Tls(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use std::path::PathBuf;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+
+    /// Write a self-signed certificate for `localhost` and its key to a
+    /// fresh temporary directory, returning the TLS configuration and the
+    /// certificate.
+    pub(crate) fn self_signed(name: &str, alpn: &[&str]) -> (TlsConfig, CertificateDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("rocks-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        let config = TlsConfig {
+            cert,
+            key,
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+        };
+        (config, certified.cert.der().clone())
+    }
+
+    /// A client connector trusting only `cert`.
+    pub(crate) fn connector(cert: CertificateDer<'static>, alpn: &[&str]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let config = TlsConfig {
+            cert: PathBuf::from("/nonexistent/cert.pem"),
+            key: PathBuf::from("/nonexistent/key.pem"),
+            alpn: vec![],
+        };
+        assert!(config.acceptor().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_with_alpn() {
+        let (config, cert) = self_signed("alpn", &["h2", "http/1.1"]);
+        let acceptor = config.acceptor().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = MaybeTlsStream::accept(stream.into(), Some(&acceptor))
+                .await
+                .unwrap();
+            assert_eq!(stream.alpn(), Some(&b"http/1.1"[..]));
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector(cert, &["http/1.1"])
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_raw_switch_writes() {
+        let (config, cert) = self_signed("raw", &[]);
+        let acceptor = config.acceptor().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (read_tls, tls_read) = tokio::sync::oneshot::channel();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = MaybeTlsStream::accept(stream.into(), Some(&acceptor))
+                .await
+                .unwrap();
+            stream.write_all(b"tls").await.unwrap();
+            stream.flush().await.unwrap();
+            tls_read.await.unwrap();
+            stream.raw_switch().unwrap().switch_writes();
+            stream.write_all(b"raw").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector(cert, &[])
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tls");
+        read_tls.send(()).unwrap();
+        let mut raw = vec![];
+        stream.get_mut().0.read_to_end(&mut raw).await.unwrap();
+        assert_eq!(raw, b"raw");
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The size of a TLS record header: type, version and length.
+const RECORD_HEADER: usize = 5;
+/// How much is read from the connection at a time.
+const READ_SIZE: usize = 16 * 1024 + 256;
+
+/// The connection under a TLS session, handed to it one record at a time.
+///
+/// Bytes are read from the connection in large chunks, but the session is
+/// only given them up to the end of the record it is reading, and, once
+/// `limit_to_one` is called, only one record until it is called again.
+/// Whatever the session has not been given stays here for `poll_read_raw`,
+/// for when the peer stops speaking TLS after some record.
+pub(crate) struct Records<S> {
+    inner: S,
+    /// Read from `inner` but not handed out yet, from `start`.
+    buffer: Vec<u8>,
+    start: usize,
+    /// What is left to hand out of the current record, header included.
+    record_left: usize,
+    /// How many more records may be started, if limited.
+    budget: Option<usize>,
+}
+
+impl<S> Records<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buffer: vec![],
+            start: 0,
+            record_left: 0,
+            budget: None,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Hand out no more than the next record until called again.
+    pub(crate) fn limit_to_one(&mut self) {
+        self.budget = Some(1);
+    }
+
+    /// Take what was read past the records handed out so far.
+    fn take_buffered(&mut self, buf: &mut ReadBuf<'_>) -> bool {
+        let buffered = &self.buffer[self.start..];
+        if buffered.is_empty() {
+            return false;
+        }
+        let n = buffered.len().min(buf.remaining());
+        buf.put_slice(&buffered[..n]);
+        self.start += n;
+        true
+    }
+}
+
+impl<S: AsyncRead + Unpin> Records<S> {
+    /// Read the connection as it is, past any record boundary, starting
+    /// with what was read from it but not handed out.
+    pub(crate) fn poll_read_raw(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.take_buffered(buf) {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+
+    /// Read more of the connection into the buffer. Returns how much was read.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
+        if self.start == self.buffer.len() {
+            self.buffer.clear();
+            self.start = 0;
+        }
+        let filled = self.buffer.len();
+        self.buffer.resize(filled + READ_SIZE, 0);
+        let mut read = ReadBuf::new(&mut self.buffer[filled..]);
+        let result = Pin::new(&mut self.inner).poll_read(cx, &mut read);
+        let n = read.filled().len();
+        self.buffer.truncate(filled + n);
+        ready!(result)?;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Records<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let buffered = &this.buffer[this.start..];
+            if this.record_left == 0 && !buffered.is_empty() && this.budget == Some(0) {
+                // The session stops reading at a pending read; have it
+                // asked again for the next record.
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if this.record_left == 0 && buffered.len() >= RECORD_HEADER {
+                this.record_left =
+                    RECORD_HEADER + u16::from_be_bytes([buffered[3], buffered[4]]) as usize;
+                if let Some(budget) = &mut this.budget {
+                    *budget -= 1;
+                }
+            }
+            let available = this.record_left.min(buffered.len());
+            if available > 0 {
+                let n = available.min(buf.remaining());
+                buf.put_slice(&buffered[..n]);
+                this.start += n;
+                this.record_left -= n;
+                return Poll::Ready(Ok(()));
+            }
+            if ready!(this.poll_fill(cx))? == 0 {
+                // The connection ended, maybe within a header; the session
+                // gets what there is and sees it cut short.
+                this.take_buffered(buf);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Records<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_one_record_at_a_time() {
+        let mut stream = vec![0x17, 0x03, 0x03, 0x00, 0x02, b'a', b'b'];
+        stream.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x01, b'c']);
+        stream.extend_from_slice(b"raw");
+        let mut records = Records::new(&stream[..]);
+
+        let mut buf = [0; 64];
+        records.limit_to_one();
+        let n = records.read(&mut buf).now_or_never().unwrap().unwrap();
+        assert_eq!(&buf[..n], &stream[..7]);
+        assert!(records.read(&mut buf).now_or_never().is_none());
+
+        records.limit_to_one();
+        let mut record = [0; 6];
+        records
+            .read_exact(&mut record[..4])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        records
+            .read_exact(&mut record[4..])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&record, &stream[7..13]);
+        assert!(records.read(&mut buf).now_or_never().is_none());
+
+        let mut raw = vec![];
+        while let Some(Ok(n)) = std::future::poll_fn(|cx| {
+            let mut read = ReadBuf::new(&mut buf);
+            records
+                .poll_read_raw(cx, &mut read)
+                .map_ok(|()| read.filled().len())
+        })
+        .now_or_never()
+        {
+            if n == 0 {
+                break;
+            }
+            raw.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(raw, b"raw");
+    }
+
+    #[test]
+    fn test_unlimited_and_cut_short() {
+        let stream = [0x16, 0x03, 0x01, 0x00, 0x01, 0x01, 0x16, 0x03];
+        let mut records = Records::new(&stream[..]);
+        let mut out = vec![];
+        records
+            .read_to_end(&mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out, stream);
+    }
+}
@@ -0,0 +1,258 @@
+// The addons section of a VLESS request: a length byte followed by a
+// protobuf message.
+//
+// ```protobuf
+// message Addons {
+//   string Flow = 1;
+//   bytes Seed = 2;
+// }
+// ```
+//
+// Only the two fields above are understood, anything else a client sends is
+// skipped the way protobuf skips unknown fields.
+
+use super::{InsufficientBuffer, VlessHeaderParseError};
+use crate::{BufferFormer, BufferParseResult, BufferParser};
+
+/// The flow used by plain VLESS, when a request carries no addons at all.
+pub const FLOW_NONE: &str = "";
+
+/// XTLS Vision, see `vision`.
+pub const FLOW_VISION: &str = "xtls-rprx-vision";
+
+/// The flows this server knows how to handle.
+pub const SUPPORTED_FLOWS: &[&str] = &[FLOW_NONE, FLOW_VISION];
+
+const FIELD_FLOW: u64 = 1;
+const FIELD_SEED: u64 = 2;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_I64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_I32: u64 = 5;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VlessAddons<'a> {
+    pub flow: &'a str,
+    pub seed: &'a [u8],
+}
+
+/// Read a varint, returning it with the number of bytes it took.
+fn read_varint(buffer: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in buffer.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn varint_size(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+fn write_varint(mut value: u64, buffer: &mut [u8]) -> usize {
+    let mut i = 0;
+    while value >= 0x80 {
+        buffer[i] = value as u8 | 0x80;
+        value >>= 7;
+        i += 1;
+    }
+    buffer[i] = value as u8;
+    i + 1
+}
+
+impl<'a> VlessAddons<'a> {
+    fn fields(&self) -> impl Iterator<Item = (u64, &'a [u8])> {
+        [(FIELD_FLOW, self.flow.as_bytes()), (FIELD_SEED, self.seed)]
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+    }
+
+    fn message_size(&self) -> usize {
+        self.fields()
+            .map(|(field, value)| {
+                varint_size(field << 3 | WIRE_LEN) + varint_size(value.len() as u64) + value.len()
+            })
+            .sum()
+    }
+
+    /// Decode the protobuf message itself, without the length byte.
+    fn decode(mut message: &'a [u8]) -> Option<Self> {
+        let mut addons = VlessAddons::default();
+        while !message.is_empty() {
+            let (key, size) = read_varint(message)?;
+            message = &message[size..];
+            let size = match key & 0x07 {
+                WIRE_VARINT => read_varint(message)?.1,
+                WIRE_I64 => 8,
+                WIRE_I32 => 4,
+                WIRE_LEN => {
+                    let (len, size) = read_varint(message)?;
+                    let end = size.checked_add(usize::try_from(len).ok()?)?;
+                    let value = message.get(size..end)?;
+                    match key >> 3 {
+                        FIELD_FLOW => addons.flow = std::str::from_utf8(value).ok()?,
+                        FIELD_SEED => addons.seed = value,
+                        _ => {}
+                    }
+                    end
+                }
+                _ => return None,
+            };
+            message = message.get(size..)?;
+        }
+        Some(addons)
+    }
+}
+
+impl<'a> BufferParser<'a> for VlessAddons<'a> {
+    type Error = VlessHeaderParseError;
+    type ParseOptions = ();
+
+    fn parse_with_options<'b>(buffer: &'b [u8], _: ()) -> BufferParseResult<Self, Self::Error>
+    where
+        Self: Sized,
+        'b: 'a,
+    {
+        let Some(&len) = buffer.first() else {
+            return BufferParseResult::Incomplete { needed: 1 };
+        };
+        let size = 1 + len as usize;
+        if buffer.len() < size {
+            return BufferParseResult::Incomplete {
+                needed: size - buffer.len(),
+            };
+        }
+        match Self::decode(&buffer[1..size]) {
+            Some(value) => BufferParseResult::Parsed { value, size },
+            None => BufferParseResult::Error(VlessHeaderParseError::InvalidAddons),
+        }
+    }
+}
+
+impl<'a> BufferFormer for VlessAddons<'a> {
+    type Error = InsufficientBuffer;
+    type FormingOptions = ();
+
+    fn size_with_option(&self, _: &Self::FormingOptions) -> usize {
+        1 + self.message_size()
+    }
+
+    fn form_with_option<'b>(
+        &'b self,
+        buffer: &'b mut [u8],
+        _: &Self::FormingOptions,
+    ) -> Result<usize, Self::Error> {
+        let len = u8::try_from(self.message_size()).map_err(|_| InsufficientBuffer)?;
+        if buffer.len() < self.size() {
+            return Err(InsufficientBuffer);
+        }
+        buffer[0] = len;
+        let mut offset = 1;
+        for (field, value) in self.fields() {
+            offset += write_varint(field << 3 | WIRE_LEN, &mut buffer[offset..]);
+            offset += write_varint(value.len() as u64, &mut buffer[offset..]);
+            buffer[offset..offset + value.len()].copy_from_slice(value);
+            offset += value.len();
+        }
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flow() {
+        let mut buffer = vec![18, 0x0a, 16];
+        buffer.extend_from_slice(b"xtls-rprx-vision");
+        buffer.push(0xff);
+        match VlessAddons::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(value.flow, "xtls-rprx-vision");
+                assert!(value.seed.is_empty());
+                assert_eq!(size, 19);
+            }
+            r => panic!("Failed to parse addons: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        match VlessAddons::parse(&[0x00]) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(value, VlessAddons::default());
+                assert_eq!(size, 1);
+            }
+            r => panic!("Failed to parse addons: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_skips_unknown_fields() {
+        let buffer = [
+            11, // length
+            0x18, 0x96, 0x01, // field 3, varint 150
+            0x25, 1, 2, 3, 4, // field 4, fixed32
+            0x12, 0x01, 0xaa, // seed
+        ];
+        match VlessAddons::parse(&buffer) {
+            BufferParseResult::Parsed { value, .. } => {
+                assert_eq!(value.flow, FLOW_NONE);
+                assert_eq!(value.seed, &[0xaa]);
+            }
+            r => panic!("Failed to parse addons: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for buffer in [
+            &[0x02, 0x0a, 0x05][..],
+            &[0x03, 0x0a, 0x01, 0xff],
+            &[0x01, 0x0b],
+            &[0x01, 0x80],
+        ] {
+            assert!(
+                matches!(VlessAddons::parse(buffer), BufferParseResult::Error(_)),
+                "{:?}",
+                buffer
+            );
+        }
+        assert!(matches!(
+            VlessAddons::parse(&[0x03, 0x0a]),
+            BufferParseResult::Incomplete { needed: 2 }
+        ));
+    }
+
+    #[test]
+    fn test_form_roundtrip() {
+        let addons = VlessAddons {
+            flow: "xtls-rprx-vision",
+            seed: &[1; 200],
+        };
+        let mut buffer = vec![0; addons.size()];
+        assert_eq!(addons.form(&mut buffer), Ok(buffer.len()));
+        match VlessAddons::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(value, addons);
+                assert_eq!(size, buffer.len());
+            }
+            r => panic!("Failed to parse formed addons: {:?}", r),
+        }
+
+        let mut buffer = [0xff];
+        assert_eq!(VlessAddons::default().form(&mut buffer), Ok(1));
+        assert_eq!(buffer, [0]);
+
+        let too_long = VlessAddons {
+            flow: "",
+            seed: &[0; 300],
+        };
+        assert_eq!(too_long.form(&mut [0; 400]), Err(InsufficientBuffer));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::buffer_parser::{BufferFormer, BufferParseResult, BufferParser};
 use derive_more::derive::Display;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::net::lookup_host;
 
 #[derive(Debug, Display)]
@@ -54,7 +54,9 @@ impl<'a> BufferParser<'a> for ProxyAddress<'a> {
                         needed: 2 + domain_len - buffer.len(),
                     };
                 }
-                let domain = std::str::from_utf8(&buffer[2..2 + domain_len]).unwrap();
+                let Ok(domain) = std::str::from_utf8(&buffer[2..2 + domain_len]) else {
+                    return BufferParseResult::Error(InvalidAddressType);
+                };
                 BufferParseResult::Parsed {
                     value: ProxyAddress::Domain(domain),
                     size: 2 + domain_len,
@@ -143,6 +145,49 @@ impl<'a> ProxyAddressWithPort<'a> {
     }
 }
 
+/// An owned copy of a [`ProxyAddressWithPort`], for use once the buffer it
+/// was parsed from is gone.
+#[derive(Debug, Display, Clone, PartialEq, Eq, Hash)]
+pub enum ProxyTarget {
+    Ip(SocketAddr),
+    #[display("{}:{}", _0, _1)]
+    Domain(String, u16),
+}
+
+impl ProxyTarget {
+    pub async fn lookup_host(&self) -> Result<Vec<SocketAddr>, std::io::Error> {
+        match self {
+            ProxyTarget::Ip(addr) => Ok(vec![*addr]),
+            ProxyTarget::Domain(domain, port) => {
+                Ok(lookup_host((domain.as_str(), *port)).await?.collect())
+            }
+        }
+    }
+}
+
+impl<'a> From<&ProxyAddressWithPort<'a>> for ProxyTarget {
+    fn from(address: &ProxyAddressWithPort<'a>) -> Self {
+        match address.address {
+            ProxyAddress::IPv4(ip) => ProxyTarget::Ip((ip, address.port).into()),
+            ProxyAddress::IPv6(ip) => ProxyTarget::Ip((ip, address.port).into()),
+            ProxyAddress::Domain(domain) => ProxyTarget::Domain(domain.to_string(), address.port),
+        }
+    }
+}
+
+impl From<SocketAddr> for ProxyAddressWithPort<'static> {
+    fn from(addr: SocketAddr) -> Self {
+        let address = match addr {
+            SocketAddr::V4(addr) => ProxyAddress::IPv4(*addr.ip()),
+            SocketAddr::V6(addr) => ProxyAddress::IPv6(*addr.ip()),
+        };
+        ProxyAddressWithPort {
+            address,
+            port: addr.port(),
+        }
+    }
+}
+
 impl<'a> BufferParser<'a> for ProxyAddressWithPort<'a> {
     type Error = InvalidAddressType;
     type ParseOptions = ();
@@ -315,6 +360,23 @@ mod tests {
         assert_eq!(buffer, vec![0x02, 0x00]);
     }
 
+    #[test]
+    fn test_proxy_target_from_address() {
+        let address = ProxyAddressWithPort {
+            address: ProxyAddress::IPv6(Ipv6Addr::LOCALHOST),
+            port: 443,
+        };
+        let target = ProxyTarget::from(&address);
+        assert_eq!(target, ProxyTarget::Ip("[::1]:443".parse().unwrap()));
+        assert_eq!(target.to_string(), "[::1]:443");
+
+        let address = ProxyAddressWithPort {
+            address: ProxyAddress::Domain("example.com"),
+            port: 80,
+        };
+        assert_eq!(ProxyTarget::from(&address).to_string(), "example.com:80");
+    }
+
     #[test]
     fn test_form_buffer_with_insufficient_buffer() {
         let address = ProxyAddress::IPv4(Ipv4Addr::new(192, 168, 1, 1));
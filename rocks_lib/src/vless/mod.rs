@@ -1,23 +1,37 @@
+mod addons;
 mod address;
 mod request;
 mod response;
+mod udp;
+mod user;
+mod vision;
 
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
+pub use addons::*;
 pub use address::*;
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 pub use request::*;
 pub use response::*;
 use thiserror::Error;
-use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::info;
-use uuid::Uuid;
+pub use udp::*;
+pub use user::*;
+use vision::{VisionPadder, VisionReader, VisionTraffic};
 
 use crate::{
-    buffer_parser::Protocol, tcp::proxy, write_ext::WriteExt, BufferParseResult, BufferParser,
+    buffer_parser::Protocol,
+    fallback::{FallbackInfo, Fallbacks},
+    mux::serve_mux,
+    proxy_protocol::{connect_relayed, Relay},
+    tcp::proxy,
+    tls::RawSwitch,
+    write_ext::WriteExt,
+    BufferFormer, BufferParseResult, BufferParser, OutboundProxyProtocolConfig,
 };
 
 #[derive(Debug, Error)]
@@ -28,73 +42,257 @@ pub enum VlessHeaderParseError {
     InvalidVersion,
     #[error("Addon is not supported")]
     AddonIsNotSupported,
+    #[error("Invalid addons")]
+    InvalidAddons,
     #[error("Invalid address")]
     InvalidAddress,
+    #[error("Too short for a VLESS header")]
+    TooShort,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub(crate) struct VlessProtocol {
-    user_id: [u8; 16],
+    users: Arc<VlessUsers>,
+    fallbacks: Option<Arc<Fallbacks>>,
+    fallback_info: FallbackInfo,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    raw_switch: Option<Arc<RawSwitch>>,
 }
 
 impl VlessProtocol {
-    pub fn new(user: &str) -> Self {
-        let mut user_id = [0u8; 16];
-        let bs: &[u8] = user.as_bytes();
-        let l = if bs.len() > 16usize {
-            16usize
-        } else {
-            bs.len()
-        };
-        user_id[..l].copy_from_slice(&bs[..l]);
-        Self { user_id }
+    pub fn new(users: Arc<VlessUsers>) -> Self {
+        Self {
+            users,
+            fallbacks: None,
+            fallback_info: FallbackInfo::default(),
+            outbound_proxy_protocol: None,
+            raw_switch: None,
+        }
+    }
+
+    /// Let Vision copy the inner TLS over the raw connection under the
+    /// outer TLS, through `switch`.
+    pub(crate) fn with_raw_switch(mut self, switch: Option<Arc<RawSwitch>>) -> Self {
+        self.raw_switch = switch;
+        self
+    }
+
+    /// Start TCP connections to the targets `config` names with a PROXY
+    /// header giving the client.
+    pub fn with_outbound_proxy_protocol(
+        mut self,
+        config: Option<Arc<OutboundProxyProtocolConfig>>,
+    ) -> Self {
+        self.outbound_proxy_protocol = config;
+        self
+    }
+
+    /// Hand connections that are not VLESS over to `fallbacks`.
+    pub fn with_fallbacks(mut self, fallbacks: Arc<Fallbacks>, info: FallbackInfo) -> Self {
+        if !fallbacks.is_empty() {
+            self.fallbacks = Some(fallbacks);
+            self.fallback_info = info;
+        }
+        self
     }
 }
 
 impl Protocol for VlessProtocol {
     async fn handle(
         &self,
-        connection: impl AsyncRead + AsyncWrite + Send + Sync + Unpin,
+        mut connection: impl AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
         remote_addr: SocketAddr,
     ) -> Result<(), Error> {
         let mut buffer = [0u8; 1024];
         let mut offset = 0;
-        let (mut in_rd, in_wr) = tokio::io::split(connection);
-        let (header, len) = loop {
-            match VlessRequestHeader::parse(&buffer[0..offset]) {
+        let options = VlessrParseOptions {
+            is_fb: self.fallbacks.is_some(),
+        };
+        let request = loop {
+            match VlessRequestHeader::parse_with_options(&buffer[0..offset], options) {
                 BufferParseResult::Incomplete { needed } => {
-                    let s = in_rd.read(&mut buffer[offset..]).await?;
+                    if offset == buffer.len() {
+                        break Err(anyhow!("VLESS header too long"));
+                    }
+                    let s = connection.read(&mut buffer[offset..]).await?;
+                    if s == 0 {
+                        Err(anyhow!("Unexpected disconnection"))?;
+                    }
                     info!("need {} read {} bytes", needed, s);
                     offset += s;
                 }
-                BufferParseResult::Error(e) => Err(e)?,
-                BufferParseResult::Parsed { value, size } => break (value, size),
+                BufferParseResult::Error(e) => break Err(e.into()),
+                BufferParseResult::Parsed { value, size } => break Ok((value, size)),
             }
         };
-        info!(
-            "user_id: {:?}, this user_id {:?}",
-            header.user,
-            Uuid::from_bytes(self.user_id)
-        );
-        let host = header.address.lookup_host().await?[0];
-        info!("{} -> ({}){}", remote_addr, header.address, host);
-        let stream = TcpStream::connect(&host).await?;
-        let (out_rd, mut out_wr) = tokio::io::split(stream);
-        out_wr.write(&buffer[len..offset]).await?;
+        let request = request.and_then(|(header, len)| {
+            let user = self
+                .users
+                .authenticate(&header.user)
+                .ok_or_else(|| anyhow!("Unknown user {} from {}", header.user, remote_addr))?;
+            Ok((header, len, user))
+        });
+        let (header, len, user) = match (request, &self.fallbacks) {
+            (Ok(request), _) => request,
+            (Err(e), Some(fallbacks)) => {
+                info!("{} from {}, falling back", e, remote_addr);
+                return fallbacks
+                    .serve(
+                        connection,
+                        &buffer[..offset],
+                        &self.fallback_info,
+                        remote_addr,
+                    )
+                    .await;
+            }
+            (Err(e), None) => return Err(e),
+        };
+        let (in_rd, in_wr) = tokio::io::split(connection);
+        info!("user_id: {:?}", user.id);
+        let flow = header.addons.flow;
+        if !user.allows_flow(flow) {
+            Err(anyhow!("User {} is not allowed flow `{}`", user.id, flow))?;
+        }
 
+        let response = VlessResponseHeader {};
+        let mut response_bytes = vec![0u8; response.size()];
+        let Ok(_) = response.form(&mut response_bytes);
         let mut first = true;
-        let in_wr = in_wr.with(|msg: &[u8]| {
+        let in_wr = in_wr.with(move |msg: &[u8]| {
             if !first {
                 return msg.to_vec();
             }
 
-            let mut msg_to_send = vec![0u8; 2];
-            msg_to_send.extend_from_slice(&msg);
+            let mut msg_to_send = response_bytes.clone();
+            msg_to_send.extend_from_slice(msg);
             first = false;
             msg_to_send
         });
 
-        proxy(in_rd, in_wr, out_rd, out_wr).await?;
-        Ok(())
+        let initial = &buffer[len..offset];
+        let relay = self.outbound_proxy_protocol.clone().map(|config| Relay {
+            config,
+            client: remote_addr,
+        });
+        match flow {
+            FLOW_NONE => serve_command(&header, in_rd, in_wr, initial, remote_addr, relay).await,
+            FLOW_VISION => {
+                if let VlessCommand::Udp = header.command {
+                    Err(anyhow!("{} does not support UDP", FLOW_VISION))?;
+                }
+                let traffic = Arc::new(Mutex::new(VisionTraffic::new()));
+                let in_rd = VisionReader::new(
+                    initial.chain(in_rd),
+                    user.id,
+                    traffic.clone(),
+                    self.raw_switch.clone(),
+                );
+                let mut padder = VisionPadder::new(user.id, traffic, self.raw_switch.clone());
+                let in_wr = in_wr.with(move |msg: &[u8]| padder.pad(msg));
+                serve_command(&header, in_rd, in_wr, &[], remote_addr, relay).await
+            }
+            _ => Err(anyhow!("Unsupported flow `{}`", flow)),
+        }
+    }
+}
+
+/// Carry out the command of a VLESS request once the response is set up.
+/// `initial` holds bytes already read from `in_rd` after the request header.
+/// TCP targets `relay` names are told about the client in a PROXY header.
+async fn serve_command(
+    header: &VlessRequestHeader<'_>,
+    in_rd: impl AsyncRead + Unpin,
+    in_wr: impl AsyncWrite + Unpin,
+    initial: &[u8],
+    remote_addr: SocketAddr,
+    relay: Option<Relay>,
+) -> Result<(), Error> {
+    match header.command {
+        VlessCommand::Tcp => {
+            let host = *header
+                .address
+                .lookup_host()
+                .await?
+                .first()
+                .ok_or_else(|| anyhow!("No address found for {}", header.address))?;
+            info!("{} -> ({}){}", remote_addr, header.address, host);
+            let stream = connect_relayed(host, relay.as_ref()).await?;
+            let (out_rd, mut out_wr) = tokio::io::split(stream);
+            out_wr.write_all(initial).await?;
+
+            proxy(in_rd, in_wr, out_rd, out_wr).await?;
+        }
+        VlessCommand::Udp => {
+            info!("{} -> udp {}", remote_addr, header.address);
+            proxy_udp(in_rd, in_wr, initial, &header.address).await?;
+        }
+        VlessCommand::Mux => {
+            info!("{} -> mux", remote_addr);
+            serve_mux(in_rd, in_wr, initial, remote_addr, relay).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, str::FromStr};
+    use tokio::net::TcpListener;
+    use vision::VisionUnpadder;
+
+    #[tokio::test]
+    async fn test_vision_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let user = VlessUser::from_str("test")
+            .unwrap()
+            .with_flows([FLOW_VISION]);
+        let id = user.id;
+        let proto = VlessProtocol::new(Arc::new(VlessUsers::new([user])));
+        let (mut client, server) = tokio::io::duplex(65536);
+        let handler = tokio::spawn(async move {
+            Protocol::handle(&proto, server, "127.0.0.1:1".parse().unwrap()).await
+        });
+
+        let header = VlessRequestHeader {
+            address: ProxyAddressWithPort {
+                address: ProxyAddress::IPv4(Ipv4Addr::LOCALHOST),
+                port,
+            },
+            user: id,
+            addons: VlessAddons {
+                flow: FLOW_VISION,
+                seed: &[],
+            },
+            command: VlessCommand::Tcp,
+        };
+        let mut request = vec![0; header.size()];
+        header.form(&mut request).unwrap();
+        let traffic = Arc::new(Mutex::new(VisionTraffic::new()));
+        request.extend(VisionPadder::new(id, traffic, None).pad(b"hello"));
+        client.write_all(&request).await.unwrap();
+
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0, 0]);
+        let mut unpadder = VisionUnpadder::new(id);
+        let mut echoed = vec![];
+        let mut buf = [0; 1024];
+        while echoed.len() < 5 {
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(n > 0, "unexpected end of stream");
+            unpadder.unpad(&buf[..n], &mut echoed);
+        }
+        assert_eq!(echoed, b"hello");
+        drop(client);
+        handler.await.unwrap().unwrap();
     }
 }
@@ -1,12 +1,13 @@
 use uuid::Uuid;
 
-use super::{InsufficientBuffer, ProxyAddressWithPort, VlessHeaderParseError};
+use super::{InsufficientBuffer, ProxyAddressWithPort, VlessAddons, VlessHeaderParseError};
 use crate::{BufferFormer, BufferParseResult, BufferParser};
 
 #[derive(Debug)]
 pub struct VlessRequestHeader<'a> {
     pub address: ProxyAddressWithPort<'a>,
     pub user: Uuid,
+    pub addons: VlessAddons<'a>,
     pub command: VlessCommand,
 }
 
@@ -19,7 +20,9 @@ pub enum VlessCommand {
 
 #[derive(Copy, Clone, Default)]
 pub struct VlessrParseOptions {
-    is_fb: bool,
+    /// The connection falls back to a web server when it is not VLESS, so a
+    /// first read too short for a request is rejected rather than waited on.
+    pub is_fb: bool,
 }
 
 impl<'a> BufferParser<'a> for VlessRequestHeader<'a> {
@@ -34,9 +37,11 @@ impl<'a> BufferParser<'a> for VlessRequestHeader<'a> {
         Self: Sized,
         'b: 'a,
     {
-        let min_size_before_cmd = (if options.is_fb { 2 } else { 1 }) * 17;
-        let min_size = min_size_before_cmd + 1;
+        let min_size = 18;
         if buffer.len() < min_size {
+            if options.is_fb && !buffer.is_empty() {
+                return BufferParseResult::Error(VlessHeaderParseError::TooShort);
+            }
             return BufferParseResult::Incomplete {
                 needed: min_size - buffer.len() + 1,
             };
@@ -47,8 +52,17 @@ impl<'a> BufferParser<'a> for VlessRequestHeader<'a> {
         let user = uuid::Builder::from_slice(&buffer[1..17])
             .unwrap()
             .into_uuid();
-        if buffer[17] != 0x00 {
-            return BufferParseResult::Error(VlessHeaderParseError::AddonIsNotSupported);
+        let (addons, min_size) = match VlessAddons::parse(&buffer[17..]) {
+            BufferParseResult::Parsed { value, size } => (value, 17 + size),
+            BufferParseResult::Incomplete { needed } => {
+                return BufferParseResult::Incomplete { needed }
+            }
+            BufferParseResult::Error(e) => return BufferParseResult::Error(e),
+        };
+        if buffer.len() <= min_size {
+            return BufferParseResult::Incomplete {
+                needed: min_size + 1 - buffer.len(),
+            };
         }
 
         let (command, address) = match buffer[min_size] {
@@ -80,6 +94,7 @@ impl<'a> BufferParser<'a> for VlessRequestHeader<'a> {
                 value: VlessRequestHeader {
                     address,
                     user,
+                    addons,
                     command,
                 },
                 size: min_size + 1 + size,
@@ -97,7 +112,7 @@ impl<'a> BufferFormer for VlessRequestHeader<'a> {
     type FormingOptions = ();
 
     fn size_with_option(&self, _: &Self::FormingOptions) -> usize {
-        18 + 1 + self.address.size()
+        17 + self.addons.size() + 1 + self.address.size()
     }
 
     fn form_with_option<'b>(
@@ -109,13 +124,96 @@ impl<'a> BufferFormer for VlessRequestHeader<'a> {
             return Err(InsufficientBuffer);
         }
         buffer[0] = 0x00;
-        buffer[1..17].copy_from_slice(&*self.user.as_bytes());
-        buffer[17] = 0x00;
-        buffer[18] = match self.command {
+        buffer[1..17].copy_from_slice(self.user.as_bytes());
+        let offset = 17 + self.addons.form(&mut buffer[17..])?;
+        buffer[offset] = match self.command {
             VlessCommand::Tcp => 0x01,
             VlessCommand::Udp => 0x02,
             VlessCommand::Mux => 0x03,
         };
-        self.address.form(&mut buffer[19..]).map(|size| 19 + size)
+        self.address
+            .form(&mut buffer[offset + 1..])
+            .map(|size| offset + 1 + size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProxyAddress;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_with_flow() {
+        let user = Uuid::new_v4();
+        let mut buffer = vec![0x00];
+        buffer.extend_from_slice(user.as_bytes());
+        buffer.extend_from_slice(&[18, 0x0a, 16]);
+        buffer.extend_from_slice(b"xtls-rprx-vision");
+        buffer.extend_from_slice(&[0x01, 0x01, 0xbb, 0x01, 127, 0, 0, 1]);
+        for len in 0..buffer.len() {
+            assert!(
+                matches!(
+                    VlessRequestHeader::parse(&buffer[..len]),
+                    BufferParseResult::Incomplete { .. }
+                ),
+                "{}",
+                len
+            );
+        }
+        match VlessRequestHeader::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(size, buffer.len());
+                assert_eq!(value.user, user);
+                assert_eq!(value.addons.flow, "xtls-rprx-vision");
+                assert!(matches!(value.command, VlessCommand::Tcp));
+                assert_eq!(value.address.port, 443);
+            }
+            r => panic!("Failed to parse header: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_short_with_fallback() {
+        let options = VlessrParseOptions { is_fb: true };
+        assert!(matches!(
+            VlessRequestHeader::parse_with_options(&[], options),
+            BufferParseResult::Incomplete { .. }
+        ));
+        assert!(matches!(
+            VlessRequestHeader::parse_with_options(&[0; 17], options),
+            BufferParseResult::Error(VlessHeaderParseError::TooShort)
+        ));
+        assert!(matches!(
+            VlessRequestHeader::parse(&[0; 17]),
+            BufferParseResult::Incomplete { .. }
+        ));
+    }
+
+    #[test]
+    fn test_form_roundtrip() {
+        let header = VlessRequestHeader {
+            address: ProxyAddressWithPort {
+                address: ProxyAddress::IPv4(Ipv4Addr::LOCALHOST),
+                port: 53,
+            },
+            user: Uuid::new_v4(),
+            addons: VlessAddons {
+                flow: "xtls-rprx-vision",
+                seed: &[],
+            },
+            command: VlessCommand::Udp,
+        };
+        let mut buffer = vec![0; header.size()];
+        assert_eq!(header.form(&mut buffer), Ok(buffer.len()));
+        match VlessRequestHeader::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(size, buffer.len());
+                assert_eq!(value.user, header.user);
+                assert_eq!(value.addons, header.addons);
+                assert!(matches!(value.command, VlessCommand::Udp));
+            }
+            r => panic!("Failed to parse formed header: {:?}", r),
+        }
     }
 }
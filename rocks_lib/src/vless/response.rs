@@ -27,10 +27,10 @@ impl<'a> BufferParser<'a> for VlessResponseHeader {
         if (buffer[1]) != 0x00 {
             return BufferParseResult::Error(VlessHeaderParseError::AddonIsNotSupported);
         }
-        return BufferParseResult::Parsed {
+        BufferParseResult::Parsed {
             value: VlessResponseHeader {},
             size: 2,
-        };
+        }
     }
 }
 
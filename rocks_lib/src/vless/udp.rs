@@ -0,0 +1,222 @@
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::{anyhow, Error};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    select,
+};
+use tracing::info;
+
+use super::{InsufficientBuffer, Never, ProxyAddressWithPort};
+use crate::{BufferFormer, BufferParseResult, BufferParser};
+
+/// The largest payload a length-prefixed packet can carry.
+pub const MAX_UDP_PAYLOAD: usize = u16::MAX as usize;
+
+/// A UDP datagram carried on a VLESS stream, prefixed with its length.
+#[derive(Debug, PartialEq)]
+pub struct VlessUdpPacket<'a> {
+    pub payload: &'a [u8],
+}
+
+impl<'a> BufferParser<'a> for VlessUdpPacket<'a> {
+    type Error = Never;
+    type ParseOptions = ();
+
+    fn parse_with_options<'b>(buffer: &'b [u8], _: ()) -> BufferParseResult<Self, Self::Error>
+    where
+        Self: Sized,
+        'b: 'a,
+    {
+        if buffer.len() < 2 {
+            return BufferParseResult::Incomplete {
+                needed: 2 - buffer.len(),
+            };
+        }
+        let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+        if buffer.len() < 2 + len {
+            return BufferParseResult::Incomplete {
+                needed: 2 + len - buffer.len(),
+            };
+        }
+        BufferParseResult::Parsed {
+            value: VlessUdpPacket {
+                payload: &buffer[2..2 + len],
+            },
+            size: 2 + len,
+        }
+    }
+}
+
+impl<'a> BufferFormer for VlessUdpPacket<'a> {
+    type Error = InsufficientBuffer;
+    type FormingOptions = ();
+
+    fn size_with_option(&self, _: &Self::FormingOptions) -> usize {
+        2 + self.payload.len()
+    }
+
+    fn form_with_option<'b>(
+        &'b self,
+        buffer: &'b mut [u8],
+        _: &Self::FormingOptions,
+    ) -> Result<usize, Self::Error> {
+        if buffer.len() < self.size() || self.payload.len() > MAX_UDP_PAYLOAD {
+            return Err(InsufficientBuffer);
+        }
+        buffer[..2].copy_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        buffer[2..self.size()].copy_from_slice(self.payload);
+        Ok(self.size())
+    }
+}
+
+/// Bind an unconnected UDP socket of the same family as `target`.
+pub(crate) async fn bind_udp_for(target: &SocketAddr) -> Result<UdpSocket, std::io::Error> {
+    let bind: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    UdpSocket::bind(bind).await
+}
+
+/// Relay length-prefixed packets read from `in_rd` to `target` over UDP, and
+/// frame every datagram received from `target` back onto `in_wr`.
+/// `initial` holds bytes already read from `in_rd` after the request header.
+pub(crate) async fn proxy_udp(
+    mut in_rd: impl AsyncRead + Unpin,
+    mut in_wr: impl AsyncWrite + Unpin,
+    initial: &[u8],
+    target: &ProxyAddressWithPort<'_>,
+) -> Result<(), Error> {
+    let host = *target
+        .lookup_host()
+        .await?
+        .first()
+        .ok_or_else(|| anyhow!("No address found for {}", target))?;
+    let socket = bind_udp_for(&host).await?;
+    socket.connect(host).await?;
+
+    let mut pending = initial.to_vec();
+    let mut buf_in = vec![0; 4096];
+    let mut buf_out = vec![0; MAX_UDP_PAYLOAD];
+    let mut frame = vec![0; 2 + MAX_UDP_PAYLOAD];
+    let mut total_in = 0;
+    let mut total_out = 0;
+
+    loop {
+        let mut consumed = 0;
+        while let BufferParseResult::Parsed { value, size } =
+            VlessUdpPacket::parse(&pending[consumed..])
+        {
+            socket.send(value.payload).await?;
+            total_in += value.payload.len();
+            consumed += size;
+        }
+        pending.drain(..consumed);
+
+        select! {
+            n = in_rd.read(&mut buf_in) => {
+                let n = n?;
+                if n == 0 {
+                    info!("udp shutdown from in (in {}/out {})", total_in, total_out);
+                    return Ok(());
+                }
+                pending.extend_from_slice(&buf_in[..n]);
+            },
+            n = socket.recv(&mut buf_out) => {
+                let n = match n {
+                    Ok(n) => n,
+                    // An ICMP error from an earlier datagram, the peer may still answer later ones.
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                    Err(e) => Err(e)?,
+                };
+                total_out += n;
+                let size = VlessUdpPacket { payload: &buf_out[..n] }
+                    .form(&mut frame)
+                    .map_err(|_| anyhow!("UDP packet too large"))?;
+                in_wr.write_all(&frame[..size]).await?;
+                in_wr.flush().await?;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProxyAddress;
+
+    #[test]
+    fn test_parse_packet() {
+        let buffer = [0x00, 0x03, 1, 2, 3, 0x00];
+        match VlessUdpPacket::parse(&buffer) {
+            BufferParseResult::Parsed { value, size } => {
+                assert_eq!(value.payload, &[1, 2, 3]);
+                assert_eq!(size, 5);
+            }
+            _ => panic!("Failed to parse packet"),
+        }
+    }
+
+    #[test]
+    fn test_incomplete_packet() {
+        match VlessUdpPacket::parse(&[0x00]) {
+            BufferParseResult::Incomplete { needed } => assert_eq!(needed, 1),
+            _ => panic!("Expected incomplete buffer"),
+        }
+        match VlessUdpPacket::parse(&[0x00, 0x04, 1, 2]) {
+            BufferParseResult::Incomplete { needed } => assert_eq!(needed, 2),
+            _ => panic!("Expected incomplete buffer"),
+        }
+    }
+
+    #[test]
+    fn test_form_packet() {
+        let packet = VlessUdpPacket { payload: b"abc" };
+        let mut buffer = vec![0; packet.size()];
+        assert_eq!(packet.form(&mut buffer), Ok(5));
+        assert_eq!(buffer, vec![0x00, 0x03, b'a', b'b', b'c']);
+        assert_eq!(packet.form(&mut [0; 4]), Err(InsufficientBuffer));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_udp_echo() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_rd, server_wr) = tokio::io::split(server);
+        let relay = tokio::spawn(async move {
+            let target = ProxyAddressWithPort {
+                address: ProxyAddress::IPv4(Ipv4Addr::LOCALHOST),
+                port: echo_addr.port(),
+            };
+            proxy_udp(server_rd, server_wr, &[0x00, 0x02, b'h', b'i'], &target).await
+        });
+
+        let (mut client_rd, mut client_wr) = tokio::io::split(client);
+        client_wr.write_all(&[0x00, 0x03, b'a']).await.unwrap();
+        client_wr.write_all(b"bc").await.unwrap();
+
+        let mut reply = [0; 9];
+        client_rd.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            &reply,
+            &[0x00, 0x02, b'h', b'i', 0x00, 0x03, b'a', b'b', b'c']
+        );
+
+        client_wr.shutdown().await.unwrap();
+        relay.await.unwrap().unwrap();
+    }
+}
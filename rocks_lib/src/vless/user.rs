@@ -0,0 +1,215 @@
+use std::str::FromStr;
+
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::FLOW_NONE;
+
+#[derive(Debug, Error, PartialEq)]
+#[error("Invalid user id `{0}`")]
+pub struct InvalidUserId(pub String);
+
+/// Convert a user id string into a UUID the same way Xray does.
+///
+/// Strings of 32 to 36 characters are parsed as a UUID: five groups of hex
+/// digits, each optionally preceded by a dash. Any other string of 1 to 30
+/// bytes is mapped to a version 5 UUID in the nil namespace, which is what
+/// `xray uuid -i <string>` prints. Everything else is rejected.
+pub fn user_id_from_str(id: &str) -> Result<Uuid, InvalidUserId> {
+    const GROUPS: [usize; 5] = [8, 4, 4, 4, 12];
+
+    let text = id.as_bytes();
+    match text.len() {
+        32..=36 => {
+            let mut bytes = [0u8; 16];
+            let mut text = text;
+            let mut offset = 0;
+            for group in GROUPS {
+                if text.first() == Some(&b'-') {
+                    text = &text[1..];
+                }
+                if text.len() < group {
+                    return Err(InvalidUserId(id.to_string()));
+                }
+                for pair in text[..group].chunks(2) {
+                    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+                    bytes[offset] = hex(pair[0])
+                        .zip(hex(pair[1]))
+                        .map(|(hi, lo)| hi << 4 | lo)
+                        .ok_or_else(|| InvalidUserId(id.to_string()))?;
+                    offset += 1;
+                }
+                text = &text[group..];
+            }
+            if !text.is_empty() {
+                return Err(InvalidUserId(id.to_string()));
+            }
+            Ok(Uuid::from_bytes(bytes))
+        }
+        1..=30 => Ok(Uuid::new_v5(&Uuid::nil(), text)),
+        _ => Err(InvalidUserId(id.to_string())),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VlessUser {
+    pub id: Uuid,
+    /// The flows this user may request, `FLOW_NONE` standing for plain VLESS.
+    pub flows: Vec<String>,
+}
+
+impl VlessUser {
+    /// A user allowed plain VLESS only.
+    pub fn new(id: Uuid) -> Self {
+        Self {
+            id,
+            flows: vec![FLOW_NONE.to_string()],
+        }
+    }
+
+    pub fn with_flows(mut self, flows: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.flows = flows.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn allows_flow(&self, flow: &str) -> bool {
+        self.flows.iter().any(|f| f == flow)
+    }
+}
+
+impl FromStr for VlessUser {
+    type Err = InvalidUserId;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        user_id_from_str(id).map(Self::new)
+    }
+}
+
+/// The set of users accepted by an inbound.
+#[derive(Debug, Clone, Default)]
+pub struct VlessUsers {
+    users: Vec<VlessUser>,
+}
+
+impl VlessUsers {
+    pub fn new(users: impl IntoIterator<Item = VlessUser>) -> Self {
+        Self {
+            users: users.into_iter().collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// The users among these whose id is in `ids`.
+    pub fn only(&self, ids: &[Uuid]) -> Self {
+        Self::new(self.users.iter().filter(|u| ids.contains(&u.id)).cloned())
+    }
+
+    /// Look up the user with the given id.
+    ///
+    /// Every configured user is compared in constant time and the loop never
+    /// exits early, so the time taken does not depend on which (if any) user
+    /// matched or on how many bytes of the id were correct.
+    pub fn authenticate(&self, id: &Uuid) -> Option<&VlessUser> {
+        let mut found = u64::MAX;
+        for (i, user) in self.users.iter().enumerate() {
+            let matched = user.id.as_bytes().ct_eq(id.as_bytes());
+            found.conditional_assign(&(i as u64), matched);
+        }
+        self.users.get(usize::try_from(found).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid(s: &str) -> Uuid {
+        Uuid::parse_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_user_id_from_uuid_string() {
+        let expected = uuid("b831381d-6324-4d53-ad4f-8cda48b30811");
+        for s in [
+            "b831381d-6324-4d53-ad4f-8cda48b30811",
+            "b831381d63244d53ad4f8cda48b30811",
+            "B831381D-6324-4D53-AD4F-8CDA48B30811",
+            "b831381d-63244d53-ad4f8cda48b30811",
+        ] {
+            assert_eq!(user_id_from_str(s), Ok(expected), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_user_id_from_string_matches_xray() {
+        assert_eq!(
+            user_id_from_str("test"),
+            Ok(uuid("e8b764da-5fe5-51ed-8af8-c5c6eca28d7a"))
+        );
+        assert_eq!(
+            user_id_from_str("example-user"),
+            Ok(uuid("83f813e8-1b93-5c42-a9a0-b5e49d98fabb"))
+        );
+    }
+
+    #[test]
+    fn test_user_id_invalid() {
+        for s in [
+            "",
+            "0123456789012345678901234567890",
+            "b831381d-6324-4d53-ad4f-8cda48b3081x",
+            "+831381d-6324-4d53-ad4f-8cda48b30811",
+            "b831381d-6324-4d53-ad4f-8cda48b308",
+            "b831381d6324-4d53-ad4f-8cda48b30811--",
+            "this string is far too long to be mapped to a user id",
+        ] {
+            assert!(user_id_from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let alice: VlessUser = "alice".parse().unwrap();
+        let bob: VlessUser = "bob".parse().unwrap();
+        let bob_id = bob.id;
+        let users = VlessUsers::new([alice, bob]);
+        assert_eq!(users.authenticate(&bob_id).map(|u| u.id), Some(bob_id));
+        assert!(users
+            .authenticate(&user_id_from_str("eve").unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_allows_flow() {
+        let user: VlessUser = "alice".parse().unwrap();
+        assert!(user.allows_flow(FLOW_NONE));
+        assert!(!user.allows_flow("xtls-rprx-vision"));
+        let user = user.with_flows(["xtls-rprx-vision"]);
+        assert!(!user.allows_flow(FLOW_NONE));
+        assert!(user.allows_flow("xtls-rprx-vision"));
+    }
+
+    #[test]
+    fn test_only() {
+        let alice: VlessUser = "alice".parse().unwrap();
+        let bob: VlessUser = "bob".parse().unwrap();
+        let bob_id = bob.id;
+        let users = VlessUsers::new([alice, bob]).only(&[bob_id, Uuid::nil()]);
+        assert_eq!(users.len(), 1);
+        assert!(users.authenticate(&bob_id).is_some());
+    }
+
+    #[test]
+    fn test_authenticate_empty() {
+        let users = VlessUsers::default();
+        assert!(users.authenticate(&Uuid::nil()).is_none());
+    }
+}
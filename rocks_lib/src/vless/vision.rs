@@ -0,0 +1,606 @@
+// XTLS Vision, the `xtls-rprx-vision` flow as implemented by Xray.
+//
+// Vision pads the first packets of a VLESS connection in both directions, so
+// that the record sizes of the outer TLS connection do not give away the TLS
+// handshake of the proxied connection. Every padded block is
+//
+//   [user id, first block only] command content_len(u16) padding_len(u16)
+//   content padding
+//
+// where the command is 0 to keep padding, 1 to stop and 2 to stop and switch
+// to copying the raw connection. Both sides watch the proxied traffic for the
+// inner TLS handshake and stop padding once its first application data
+// record goes through. When the inner TLS is 1.3, the side ending its padding
+// with command 2 writes the inner records straight to the TCP connection from
+// then on, outside the outer TLS, and the other side reads them from there.
+// Without outer TLS there is nothing to switch, and 2 ends padding like 1.
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use rand::Rng;
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::tls::RawSwitch;
+
+const COMMAND_CONTINUE: u8 = 0;
+const COMMAND_END: u8 = 1;
+const COMMAND_DIRECT: u8 = 2;
+
+/// The size of a padded block, and of the largest read Vision looks at.
+const BLOCK_SIZE: usize = 8192;
+/// The user id and the block header.
+const BLOCK_OVERHEAD: usize = 21;
+const BLOCK_HEADER: usize = 5;
+/// Padding lengths: below `[0]` bytes of content a block is padded to
+/// `[2]` plus up to `[1]` bytes, otherwise with up to `[3]` bytes.
+const TESTSEED: [usize; 4] = [900, 500, 900, 256];
+const PACKETS_TO_FILTER: i32 = 8;
+
+const TLS_SERVER_HANDSHAKE_START: [u8; 3] = [0x16, 0x03, 0x03];
+const TLS_CLIENT_HANDSHAKE_START: [u8; 2] = [0x16, 0x03];
+const TLS_APPLICATION_DATA_START: [u8; 3] = [0x17, 0x03, 0x03];
+const TLS_HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const TLS_HANDSHAKE_TYPE_SERVER_HELLO: u8 = 0x02;
+const TLS13_SUPPORTED_VERSIONS: [u8; 6] = [0x00, 0x2b, 0x00, 0x02, 0x03, 0x04];
+/// The TLS 1.3 cipher suites, except `TLS_AES_128_CCM_8_SHA256`.
+const TLS13_XTLS_CIPHERS: [u16; 4] = [0x1301, 0x1302, 0x1303, 0x1304];
+
+/// What has been learnt about the proxied connection, shared by both
+/// directions.
+#[derive(Debug)]
+pub(crate) struct VisionTraffic {
+    packets_to_filter: i32,
+    is_tls: bool,
+    is_tls12_or_above: bool,
+    enable_xtls: bool,
+    cipher: u16,
+    remaining_server_hello: i32,
+}
+
+impl VisionTraffic {
+    pub(crate) fn new() -> Self {
+        Self {
+            packets_to_filter: PACKETS_TO_FILTER,
+            is_tls: false,
+            is_tls12_or_above: false,
+            enable_xtls: false,
+            cipher: 0,
+            remaining_server_hello: 0,
+        }
+    }
+
+    /// Look for the handshake of an inner TLS connection in `packet`.
+    fn filter_tls(&mut self, packet: &[u8]) {
+        if self.packets_to_filter <= 0 {
+            return;
+        }
+        self.packets_to_filter -= 1;
+        if packet.len() >= 6 {
+            if packet[..3] == TLS_SERVER_HANDSHAKE_START
+                && packet[5] == TLS_HANDSHAKE_TYPE_SERVER_HELLO
+            {
+                self.remaining_server_hello =
+                    (i32::from(packet[3]) << 8 | i32::from(packet[4])) + 5;
+                self.is_tls12_or_above = true;
+                self.is_tls = true;
+                if packet.len() >= 79 && self.remaining_server_hello >= 79 {
+                    let cipher_at = 43 + packet[43] as usize + 1;
+                    if let Some(cipher) = packet.get(cipher_at..cipher_at + 2) {
+                        self.cipher = u16::from_be_bytes([cipher[0], cipher[1]]);
+                    }
+                }
+            } else if packet[..2] == TLS_CLIENT_HANDSHAKE_START
+                && packet[5] == TLS_HANDSHAKE_TYPE_CLIENT_HELLO
+            {
+                self.is_tls = true;
+            }
+        }
+        if self.remaining_server_hello > 0 {
+            let end = (self.remaining_server_hello as usize).min(packet.len());
+            self.remaining_server_hello -= packet.len() as i32;
+            if packet[..end]
+                .windows(TLS13_SUPPORTED_VERSIONS.len())
+                .any(|w| w == TLS13_SUPPORTED_VERSIONS)
+            {
+                self.enable_xtls = TLS13_XTLS_CIPHERS.contains(&self.cipher);
+                info!(
+                    "vision found tls 1.3 (cipher {:#06x}, xtls {})",
+                    self.cipher, self.enable_xtls
+                );
+                self.packets_to_filter = 0;
+            } else if self.remaining_server_hello <= 0 {
+                info!("vision found tls 1.2");
+                self.packets_to_filter = 0;
+            }
+        }
+    }
+}
+
+/// Whether `data` is made of whole TLS application data records.
+fn is_complete_record(mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        if data.len() < 5 || data[..3] != TLS_APPLICATION_DATA_START {
+            return false;
+        }
+        let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+        if len == 0 || data.len() < 5 + len {
+            return false;
+        }
+        data = &data[5 + len..];
+    }
+    true
+}
+
+/// Pads what the server sends, one write at a time.
+pub(crate) struct VisionPadder {
+    traffic: Arc<Mutex<VisionTraffic>>,
+    user: Option<Uuid>,
+    is_padding: bool,
+    /// Where writes go once padding ends with command 2.
+    raw: Option<Arc<RawSwitch>>,
+    /// Whether the last write ended padding with command 2.
+    sent_direct: bool,
+}
+
+impl VisionPadder {
+    pub(crate) fn new(
+        user: Uuid,
+        traffic: Arc<Mutex<VisionTraffic>>,
+        raw: Option<Arc<RawSwitch>>,
+    ) -> Self {
+        Self {
+            traffic,
+            user: Some(user),
+            is_padding: true,
+            raw,
+            sent_direct: false,
+        }
+    }
+
+    pub(crate) fn pad(&mut self, data: &[u8]) -> Vec<u8> {
+        if std::mem::take(&mut self.sent_direct) {
+            // The write ending padding went through TLS, this one does not.
+            if let Some(raw) = &self.raw {
+                raw.switch_writes();
+            }
+        }
+        let mut traffic = self.traffic.lock().unwrap();
+        traffic.filter_tls(data);
+        if !self.is_padding {
+            return data.to_vec();
+        }
+        let end = if traffic.enable_xtls && self.raw.is_some() {
+            COMMAND_DIRECT
+        } else {
+            COMMAND_END
+        };
+
+        let is_complete = is_complete_record(data);
+        let chunks: Vec<_> = data.chunks(BLOCK_SIZE - BLOCK_OVERHEAD).collect();
+        let mut long_padding = traffic.is_tls;
+        let mut out = Vec::with_capacity(data.len() + chunks.len() * BLOCK_SIZE);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let last = i == chunks.len() - 1;
+            if traffic.is_tls && chunk.starts_with(&TLS_APPLICATION_DATA_START) && is_complete {
+                let command = if last { end } else { COMMAND_CONTINUE };
+                pad_block(self.user.take(), chunk, command, true, &mut out);
+                self.is_padding = false;
+                long_padding = false;
+                continue;
+            } else if !traffic.is_tls12_or_above && traffic.packets_to_filter <= 1 {
+                // Not TLS 1.2 or above, finish a packet early like Xray does
+                // for compatibility with older receivers.
+                self.is_padding = false;
+                pad_block(self.user.take(), chunk, COMMAND_END, long_padding, &mut out);
+                chunks[i + 1..]
+                    .iter()
+                    .for_each(|c| out.extend_from_slice(c));
+                break;
+            }
+            let command = if last && !self.is_padding {
+                end
+            } else {
+                COMMAND_CONTINUE
+            };
+            pad_block(self.user.take(), chunk, command, long_padding, &mut out);
+        }
+        self.sent_direct = !self.is_padding && end == COMMAND_DIRECT;
+        out
+    }
+}
+
+/// Append `content` to `out` as one padded block, prefixed with `user` if set.
+fn pad_block(
+    user: Option<Uuid>,
+    content: &[u8],
+    command: u8,
+    long_padding: bool,
+    out: &mut Vec<u8>,
+) {
+    let mut rng = rand::thread_rng();
+    let padding = if content.len() < TESTSEED[0] && long_padding {
+        rng.gen_range(0..TESTSEED[1]) + TESTSEED[2] - content.len()
+    } else {
+        rng.gen_range(0..TESTSEED[3])
+    };
+    let padding = padding.min(BLOCK_SIZE.saturating_sub(BLOCK_OVERHEAD + content.len()));
+    if let Some(user) = user {
+        out.extend_from_slice(user.as_bytes());
+    }
+    out.push(command);
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&(padding as u16).to_be_bytes());
+    out.extend_from_slice(content);
+    out.resize(out.len() + padding, 0);
+}
+
+/// Strips the padding from what the client sends.
+pub(crate) struct VisionUnpadder {
+    user: Option<Uuid>,
+    is_padding: bool,
+    /// Whether padding ended with command 2.
+    is_direct: bool,
+    /// The start of the stream, until it is long enough to hold the user id
+    /// and a block header.
+    pending: Vec<u8>,
+    remaining_command: usize,
+    remaining_content: usize,
+    remaining_padding: usize,
+    current_command: u8,
+}
+
+impl VisionUnpadder {
+    pub(crate) fn new(user: Uuid) -> Self {
+        Self {
+            user: Some(user),
+            is_padding: true,
+            is_direct: false,
+            pending: vec![],
+            remaining_command: 0,
+            remaining_content: 0,
+            remaining_padding: 0,
+            current_command: COMMAND_CONTINUE,
+        }
+    }
+
+    /// Append the content carried by `input` to `out`.
+    pub(crate) fn unpad(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let Some(user) = self.user else {
+            return self.unpad_blocks(input, out);
+        };
+        self.pending.extend_from_slice(input);
+        let id_len = self.pending.len().min(16);
+        if self.pending[..id_len] == user.as_bytes()[..id_len] {
+            if self.pending.len() < 16 + BLOCK_HEADER {
+                return;
+            }
+            self.remaining_command = BLOCK_HEADER;
+        } else {
+            info!("vision client did not pad its first packet");
+            self.is_padding = false;
+        }
+        self.user = None;
+        let pending = std::mem::take(&mut self.pending);
+        let start = if self.is_padding { 16 } else { 0 };
+        self.unpad_blocks(&pending[start..], out);
+    }
+
+    /// Give up on a stream that ended before its first block.
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.pending);
+    }
+
+    fn unpad_blocks(&mut self, mut input: &[u8], out: &mut Vec<u8>) {
+        while self.is_padding && !input.is_empty() {
+            if self.remaining_command > 0 {
+                let byte = input[0];
+                input = &input[1..];
+                match self.remaining_command {
+                    5 => self.current_command = byte,
+                    4 => self.remaining_content = (byte as usize) << 8,
+                    3 => self.remaining_content |= byte as usize,
+                    2 => self.remaining_padding = (byte as usize) << 8,
+                    _ => self.remaining_padding |= byte as usize,
+                }
+                self.remaining_command -= 1;
+            } else if self.remaining_content > 0 {
+                let n = self.remaining_content.min(input.len());
+                out.extend_from_slice(&input[..n]);
+                input = &input[n..];
+                self.remaining_content -= n;
+            } else {
+                let n = self.remaining_padding.min(input.len());
+                input = &input[n..];
+                self.remaining_padding -= n;
+            }
+            if self.remaining_command == 0
+                && self.remaining_content == 0
+                && self.remaining_padding == 0
+            {
+                match self.current_command {
+                    COMMAND_CONTINUE => self.remaining_command = BLOCK_HEADER,
+                    COMMAND_END => self.is_padding = false,
+                    COMMAND_DIRECT => {
+                        self.is_padding = false;
+                        self.is_direct = true;
+                    }
+                    command => {
+                        info!("vision client sent unknown command {}", command);
+                        self.is_padding = false;
+                    }
+                }
+            }
+        }
+        out.extend_from_slice(input);
+    }
+}
+
+/// Reads a Vision padded stream, handing out the content only.
+#[pin_project::pin_project]
+pub(crate) struct VisionReader<R> {
+    #[pin]
+    inner: R,
+    traffic: Arc<Mutex<VisionTraffic>>,
+    unpadder: VisionUnpadder,
+    /// Where reads come from once the client ends padding with command 2.
+    switch: Option<Arc<RawSwitch>>,
+    raw: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl<R> VisionReader<R> {
+    pub(crate) fn new(
+        inner: R,
+        user: Uuid,
+        traffic: Arc<Mutex<VisionTraffic>>,
+        switch: Option<Arc<RawSwitch>>,
+    ) -> Self {
+        Self {
+            inner,
+            traffic,
+            unpadder: VisionUnpadder::new(user),
+            switch,
+            raw: vec![0; BLOCK_SIZE],
+            out: vec![],
+            out_pos: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for VisionReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut this = self.project();
+        while *this.out_pos == this.out.len() {
+            this.out.clear();
+            *this.out_pos = 0;
+            let mut raw = ReadBuf::new(this.raw);
+            ready!(this.inner.as_mut().poll_read(cx, &mut raw))?;
+            if raw.filled().is_empty() {
+                this.unpadder.finish(this.out);
+                if this.out.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+            } else {
+                this.unpadder.unpad(raw.filled(), this.out);
+                if std::mem::take(&mut this.unpadder.is_direct) {
+                    match this.switch {
+                        Some(switch) => switch.switch_reads(),
+                        None => info!("vision client asked for direct copy without outer tls"),
+                    }
+                }
+            }
+            if !this.out.is_empty() {
+                this.traffic.lock().unwrap().filter_tls(this.out);
+            }
+        }
+        let n = buf.remaining().min(this.out.len() - *this.out_pos);
+        buf.put_slice(&this.out[*this.out_pos..*this.out_pos + n]);
+        *this.out_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{
+        tests::{connector, self_signed},
+        MaybeTlsStream,
+    };
+    use rustls::pki_types::ServerName;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn traffic() -> Arc<Mutex<VisionTraffic>> {
+        Arc::new(Mutex::new(VisionTraffic::new()))
+    }
+
+    fn server_hello(cipher: u16, tls13: bool) -> Vec<u8> {
+        let mut hello = vec![
+            0x16, 0x03, 0x03, 0x00, 0x5a, 0x02, 0x00, 0x00, 0x56, 0x03, 0x03,
+        ];
+        hello.extend_from_slice(&[0xaa; 32]);
+        hello.push(32);
+        hello.extend_from_slice(&[0xbb; 32]);
+        hello.extend_from_slice(&cipher.to_be_bytes());
+        hello.push(0x00);
+        hello.extend_from_slice(&[0x00, 0x2e]);
+        if tls13 {
+            hello.extend_from_slice(&TLS13_SUPPORTED_VERSIONS);
+        } else {
+            hello.extend_from_slice(&[0xff, 0x01, 0x00, 0x01, 0x00, 0x00]);
+        }
+        hello.resize(5 + 0x5a, 0);
+        hello
+    }
+
+    #[test]
+    fn test_filter_tls13() {
+        let mut traffic = VisionTraffic::new();
+        traffic.filter_tls(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00]);
+        assert!(traffic.is_tls);
+        assert!(!traffic.is_tls12_or_above);
+        traffic.filter_tls(&server_hello(0x1301, true));
+        assert!(traffic.is_tls12_or_above);
+        assert!(traffic.enable_xtls);
+        assert_eq!(traffic.cipher, 0x1301);
+        assert_eq!(traffic.packets_to_filter, 0);
+    }
+
+    #[test]
+    fn test_filter_tls12_and_ccm8() {
+        let mut traffic = VisionTraffic::new();
+        traffic.filter_tls(&server_hello(0xc02f, false));
+        assert!(traffic.is_tls12_or_above);
+        assert!(!traffic.enable_xtls);
+        assert_eq!(traffic.packets_to_filter, 0);
+
+        let mut traffic = VisionTraffic::new();
+        traffic.filter_tls(&server_hello(0x1305, true));
+        assert!(!traffic.enable_xtls);
+    }
+
+    #[test]
+    fn test_is_complete_record() {
+        assert!(is_complete_record(&[0x17, 0x03, 0x03, 0x00, 0x01, 0xff]));
+        assert!(is_complete_record(&[
+            0x17, 0x03, 0x03, 0x00, 0x01, 0xff, 0x17, 0x03, 0x03, 0x00, 0x02, 0, 0
+        ]));
+        assert!(!is_complete_record(&[0x17, 0x03, 0x03, 0x00, 0x02, 0xff]));
+        assert!(!is_complete_record(&[0x16, 0x03, 0x03, 0x00, 0x01, 0xff]));
+    }
+
+    #[test]
+    fn test_pad_roundtrip() {
+        let user = Uuid::new_v4();
+        let mut padder = VisionPadder::new(user, traffic(), None);
+        let mut stream = padder.pad(b"hello");
+        assert_eq!(&stream[..16], user.as_bytes());
+        assert_eq!(stream[16], COMMAND_CONTINUE);
+        assert_eq!(&stream[17..19], &[0, 5]);
+        let large = vec![7; 10000];
+        stream.extend(padder.pad(&large));
+
+        let mut unpadder = VisionUnpadder::new(user);
+        let mut out = vec![];
+        for chunk in stream.chunks(3) {
+            unpadder.unpad(chunk, &mut out);
+        }
+        assert_eq!(&out[..5], b"hello");
+        assert_eq!(&out[5..], &large[..]);
+    }
+
+    #[test]
+    fn test_pad_ends_after_application_data() {
+        let user = Uuid::new_v4();
+        let traffic = traffic();
+        let mut padder = VisionPadder::new(user, traffic.clone(), None);
+        let mut stream = padder.pad(&server_hello(0x1301, true));
+        assert!(padder.is_padding);
+        stream.extend(padder.pad(&[0x17, 0x03, 0x03, 0x00, 0x01, 0xff]));
+        assert!(!padder.is_padding);
+        assert_eq!(padder.pad(b"raw"), b"raw");
+        stream.extend_from_slice(b"raw");
+
+        let mut unpadder = VisionUnpadder::new(user);
+        let mut out = vec![];
+        unpadder.unpad(&stream, &mut out);
+        assert!(!unpadder.is_padding);
+        let mut expected = server_hello(0x1301, true);
+        expected.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x01, 0xff]);
+        expected.extend_from_slice(b"raw");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_unpad_unpadded() {
+        let user = Uuid::new_v4();
+        let mut unpadder = VisionUnpadder::new(user);
+        let mut out = vec![];
+        unpadder.unpad(b"plain", &mut out);
+        assert_eq!(out, b"plain");
+    }
+
+    #[test]
+    fn test_pad_direct() {
+        let user = Uuid::new_v4();
+        let traffic = traffic();
+        let raw = Arc::new(RawSwitch::default());
+        let mut padder = VisionPadder::new(user, traffic, Some(raw));
+        padder.pad(&server_hello(0x1301, true));
+        let record = [0x17, 0x03, 0x03, 0x00, 0x01, 0xff];
+        let stream = padder.pad(&record);
+        assert_eq!(stream[0], COMMAND_DIRECT);
+        assert!(padder.sent_direct);
+        assert_eq!(padder.pad(b"raw"), b"raw");
+        assert!(!padder.sent_direct);
+    }
+
+    #[tokio::test]
+    async fn test_reader_switches_to_raw_after_direct() {
+        let (config, cert) = self_signed("vision", &[]);
+        let acceptor = config.acceptor().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let user = Uuid::new_v4();
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = connector(cert, &[])
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            let mut block = user.as_bytes().to_vec();
+            block.extend_from_slice(&[COMMAND_DIRECT, 0, 2, 0, 1, b'h', b'i', 0]);
+            stream.write_all(&block).await.unwrap();
+            stream.flush().await.unwrap();
+            // An inner TLS record, straight on the TCP connection.
+            let raw = stream.get_mut().0;
+            raw.write_all(&[0x17, 0x03, 0x03, 0x00, 0x02, b'o', b'k'])
+                .await
+                .unwrap();
+            raw.shutdown().await.unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = MaybeTlsStream::accept(stream.into(), Some(&acceptor))
+            .await
+            .unwrap();
+        let switch = stream.raw_switch();
+        let mut reader = VisionReader::new(stream, user, traffic(), switch);
+        let mut out = vec![];
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, [b'h', b'i', 0x17, 0x03, 0x03, 0x00, 0x02, b'o', b'k']);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vision_reader() {
+        let user = Uuid::new_v4();
+        let mut padder = VisionPadder::new(user, traffic(), None);
+        let mut stream = padder.pad(b"hello ");
+        stream.extend(padder.pad(b"world"));
+        let mut reader = VisionReader::new(&stream[..], user, traffic(), None);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "hello world");
+
+        let mut reader = VisionReader::new(&user.as_bytes()[..4], user, traffic(), None);
+        let mut out = vec![];
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, &user.as_bytes()[..4]);
+    }
+}
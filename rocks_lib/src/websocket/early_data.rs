@@ -0,0 +1,145 @@
+// WebSocket 0-RTT. Xray and v2ray clients can send the first bytes of a
+// session base64url-encoded along with the upgrade request, saving the round
+// trip of waiting for the upgrade before the first frame.
+
+use anyhow::{anyhow, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tracing::debug;
+
+use crate::http::RequestHead;
+
+/// Early data sent along with an upgrade request.
+#[derive(Debug)]
+pub(crate) struct EarlyData {
+    pub(crate) data: Vec<u8>,
+    /// The `Sec-WebSocket-Protocol` to answer with, when the data came in it.
+    pub(crate) protocol: Option<HeaderValue>,
+}
+
+fn decode(encoded: &[u8]) -> Result<Vec<u8>, Error> {
+    let encoded = encoded.strip_suffix(b"==").unwrap_or(encoded);
+    let encoded = encoded.strip_suffix(b"=").unwrap_or(encoded);
+    URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| anyhow!("Invalid early data: {}", e))
+}
+
+impl EarlyData {
+    /// The early data of `request`, taken from `Sec-WebSocket-Protocol` or
+    /// else from the query parameter named `query`. Like Xray, a protocol
+    /// that does not decode is not early data, but a subprotocol the client
+    /// asks for, and left alone; a query value that does not decode is not
+    /// early data either, and the upgrade goes on without it.
+    pub(crate) fn from_request(
+        request: &RequestHead,
+        query: Option<&str>,
+    ) -> Result<Option<Self>, Error> {
+        if let Some(protocol) = request.header("sec-websocket-protocol") {
+            match decode(protocol) {
+                Ok(data) if !data.is_empty() => {
+                    return Ok(Some(Self {
+                        data,
+                        protocol: Some(HeaderValue::from_bytes(protocol)?),
+                    }))
+                }
+                Ok(_) => {}
+                Err(e) => debug!("{}, taking the protocol for a subprotocol", e),
+            }
+        }
+        let Some(name) = query else {
+            return Ok(None);
+        };
+        let value = request.target.split_once('?').and_then(|(_, query)| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        });
+        match value.filter(|value| !value.is_empty()).map(str::as_bytes) {
+            Some(value) => match decode(value) {
+                Ok(data) => Ok(Some(Self {
+                    data,
+                    protocol: None,
+                })),
+                Err(e) => {
+                    debug!("{} in the query, taking no early data", e);
+                    Ok(None)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_from_header() {
+        let encoded = URL_SAFE_NO_PAD.encode([0, 0xfb, 0xff, 1]);
+        let request = request("/vless", &[("Sec-WebSocket-Protocol", &encoded)]);
+        let early_data = EarlyData::from_request(&request, Some("ed"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(early_data.data, [0, 0xfb, 0xff, 1]);
+        assert_eq!(early_data.protocol.unwrap(), encoded.as_str());
+    }
+
+    #[test]
+    fn test_undecodable_header() {
+        let request = request(
+            "/vless?ed=AAEC",
+            &[("Sec-WebSocket-Protocol", "chat, superchat")],
+        );
+        assert!(EarlyData::from_request(&request, None).unwrap().is_none());
+        let early_data = EarlyData::from_request(&request, Some("ed"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(early_data.data, [0, 1, 2]);
+        assert!(early_data.protocol.is_none());
+    }
+
+    #[test]
+    fn test_undecodable_query() {
+        let request = request("/vless?ed=A*", &[]);
+        assert!(EarlyData::from_request(&request, Some("ed"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_from_query() {
+        let request = |target: &str| request(target, &[]);
+        let early_data = EarlyData::from_request(&request("/vless?x=1&ed=AAEC"), Some("ed"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(early_data.data, [0, 1, 2]);
+        assert!(early_data.protocol.is_none());
+        assert!(EarlyData::from_request(&request("/vless?ed=AAEC"), None)
+            .unwrap()
+            .is_none());
+        assert!(
+            EarlyData::from_request(&request("/vless?edx=AAEC"), Some("ed"))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_padding_accepted() {
+        assert_eq!(decode(b"AAE=").unwrap(), [0, 1]);
+        assert_eq!(decode(b"AA==").unwrap(), [0]);
+    }
+}
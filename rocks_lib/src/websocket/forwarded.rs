@@ -0,0 +1,113 @@
+// The client address of requests relayed by reverse proxies. A proxy we trust
+// puts the address it got the request from in a header such as
+// `X-Forwarded-For` or `X-Real-IP`; anyone else's headers are ignored.
+
+use std::net::{IpAddr, SocketAddr};
+
+use tracing::debug;
+
+use crate::{http::RequestHead, IpCidr};
+
+fn parse_addr(entry: &str) -> Option<SocketAddr> {
+    let entry = entry.trim();
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = entry.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// The address of the client behind `peer`. When `peer` is one of `trusted`,
+/// the `header` entries are walked from the nearest hop back, skipping the
+/// trusted proxies, and the first other address is the client. Its port is
+/// 0 unless the header gives one.
+pub(crate) fn client_addr(
+    peer: SocketAddr,
+    request: &RequestHead,
+    trusted: &[IpCidr],
+    header: &str,
+) -> SocketAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(&peer.ip()) {
+        return peer;
+    }
+    let Some(value) = request
+        .header(header)
+        .and_then(|v| std::str::from_utf8(v).ok())
+    else {
+        return peer;
+    };
+    let hops: Vec<SocketAddr> = value.split(',').filter_map(parse_addr).collect();
+    let client = hops
+        .iter()
+        .rev()
+        .find(|hop| !is_trusted(&hop.ip()))
+        .or(hops.first())
+        .copied();
+    match client {
+        Some(client) => {
+            debug!("{} forwards for {}", peer, client);
+            client
+        }
+        None => peer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let trusted = ["10.0.0.0/8", "::1"].map(|c| IpCidr::try_from(c.to_string()).unwrap());
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let xff = "X-Forwarded-For";
+        let forwarded = request(&[("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.2")]);
+
+        assert_eq!(
+            client_addr(addr("10.0.0.1:5000"), &forwarded, &trusted, xff),
+            addr("2.2.2.2:0")
+        );
+        assert_eq!(
+            client_addr(addr("[::1]:5000"), &forwarded, &trusted, xff),
+            addr("2.2.2.2:0")
+        );
+        // Only trusted proxies are listened to.
+        assert_eq!(
+            client_addr(addr("3.3.3.3:5000"), &forwarded, &trusted, xff),
+            addr("3.3.3.3:5000")
+        );
+        // Every hop trusted: the first one is as good as it gets.
+        let internal = request(&[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            client_addr(addr("10.0.0.1:5000"), &internal, &trusted, xff),
+            addr("10.0.0.3:0")
+        );
+
+        let real_ip = request(&[("X-Real-IP", "[2001:db8::1]:443")]);
+        assert_eq!(
+            client_addr(addr("10.0.0.1:5000"), &real_ip, &trusted, "X-Real-IP"),
+            addr("[2001:db8::1]:443")
+        );
+        assert_eq!(
+            client_addr(addr("10.0.0.1:5000"), &real_ip, &trusted, xff),
+            addr("10.0.0.1:5000")
+        );
+        let garbage = request(&[("X-Real-IP", "unknown")]);
+        assert_eq!(
+            client_addr(addr("10.0.0.1:5000"), &garbage, &trusted, "X-Real-IP"),
+            addr("10.0.0.1:5000")
+        );
+    }
+}
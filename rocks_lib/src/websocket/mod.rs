@@ -1,114 +1,137 @@
-use std::{future::ready, net::SocketAddr};
-
-use anyhow::{anyhow, Error};
-use futures::{Sink, SinkExt, Stream, StreamExt};
-use hex_display::HexDisplayExt;
-use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
-    select,
+mod early_data;
+mod forwarded;
+mod route;
+
+pub(crate) use early_data::EarlyData;
+pub(crate) use forwarded::client_addr;
+pub use route::WsRoutes;
+pub(crate) use route::{Routed, WsSession};
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
 };
-use tracing::info;
 
-use crate::{BufferParseResult, BufferParser, VlessRequestHeader};
+use anyhow::Error;
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{buffer_parser::Protocol, OutboundProxyProtocolConfig, VlessProtocol, VlessUsers};
 
 pub async fn handle_stream_sink(
-    mut in_rd: impl Stream<Item = Result<Vec<u8>, Error>> + Send + Sync + Unpin,
-    in_wr: impl Sink<Vec<u8>, Error = Error> + Send + Sync + Unpin,
+    in_rd: impl Stream<Item = Result<Vec<u8>, Error>> + Send + Sync + Unpin + 'static,
+    in_wr: impl Sink<Vec<u8>, Error = Error> + Send + Sync + Unpin + 'static,
     remote_addr: SocketAddr,
+    users: Arc<VlessUsers>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
 ) -> Result<(), anyhow::Error> {
-    let mut data = in_rd
-        .next()
-        .await
-        .unwrap_or_else(|| Err(anyhow!("Unexpected disconnection")))?;
-    let (header, s) = loop {
-        match VlessRequestHeader::parse(&data) {
-            BufferParseResult::Incomplete { .. } => {
-                let mut more = in_rd
-                    .next()
-                    .await
-                    .unwrap_or_else(|| Err(anyhow!("Unexpected disconnection")))?;
-                data.append(&mut more);
-                continue;
-            }
-            BufferParseResult::Parsed { value, size } => {
-                break (value, size);
-            }
-            BufferParseResult::Error(e) => {
-                info!("Error parsing buffer: {:?}", e);
-                return Err(anyhow::Error::msg("Error parsing buffer"));
-            }
-        }
-    };
-
-    info!("user_id: {:?}", header.user);
-
-    let host = header.address.lookup_host().await?[0];
-    info!("{} -> ({}){}", remote_addr, header.address, host);
-    let stream = TcpStream::connect(&host).await?;
-    let (out_rd, mut out_wr) = tokio::io::split(stream);
-    out_wr.write(&data[s..]).await?;
-    let mut first = true;
-    let in_wr = in_wr.with(|msg: Vec<u8>| {
-        if first {
-            info!("first message: {}", msg.hex());
-            first = false;
-            let mut msg_to_send = vec![0u8; 2];
-            msg_to_send.extend_from_slice(&msg);
-            return ready(Ok(msg_to_send));
+    let proto = VlessProtocol::new(users).with_outbound_proxy_protocol(outbound_proxy_protocol);
+    Protocol::handle(&proto, StreamSinkIo::new(in_rd, in_wr), remote_addr).await
+}
+
+/// Presents a stream of messages and a sink of messages as a byte stream.
+///
+/// Each message read from the stream is handed out as bytes, and every write
+/// is sent as one message. Messages are only flushed on `poll_flush`.
+#[pin_project::pin_project]
+pub(crate) struct StreamSinkIo<St, Si> {
+    #[pin]
+    stream: St,
+    #[pin]
+    sink: Si,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<St, Si> StreamSinkIo<St, Si> {
+    pub(crate) fn new(stream: St, sink: Si) -> Self {
+        Self {
+            stream,
+            sink,
+            read_buf: vec![],
+            read_pos: 0,
         }
-        ready(Ok(msg))
-    });
+    }
+}
 
-    proxy_sink_stream(in_rd, in_wr, out_rd, out_wr).await
+fn to_io_error(e: Error) -> std::io::Error {
+    std::io::Error::other(e)
 }
 
-async fn proxy_sink_stream(
-    mut in_rd: impl Stream<Item = Result<Vec<u8>, Error>> + Send + Sync + Unpin,
-    mut in_wr: impl Sink<Vec<u8>, Error = Error> + Send + Sync + Unpin,
-    mut out_rd: impl AsyncRead + Send + Sync + Unpin,
-    mut out_wr: impl AsyncWrite + Send + Sync + Unpin,
-) -> Result<(), Error> {
-    let mut total_in = 0;
-    let mut total_out = 0;
-
-    loop {
-        let buf_out_read = &mut [0; 1024];
-        select! {
-            msg = in_rd.next() => {
-                let msg = match msg {
-                    Some(Ok(msg)) => msg,
-                    Some(Err(e)) => {
-                        info!("Error reading from in: {:?}", e);
-                        break;
-                    }
-                    None => {
-                        info!("in stream ended");
-                        break;
-                    }
-                };
-                total_in += msg.len();
-                out_wr.write_all(&msg).await.unwrap();
-            }
-            msg = out_rd.read(buf_out_read) => {
-                let n = match msg {
-                    Ok(n) => n,
-                    Err(e) => {
-                        info!("Error reading from out: {:?}", e);
-                        break;
-                    }
-                };
-                if n == 0 {
-                    info!("out stream ended");
-                    in_wr.send(vec![]).await?;
-                    break;
+impl<St, Si> AsyncRead for StreamSinkIo<St, Si>
+where
+    St: Stream<Item = Result<Vec<u8>, Error>>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut this = self.project();
+        while *this.read_pos == this.read_buf.len() {
+            match ready!(this.stream.as_mut().poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    *this.read_buf = msg;
+                    *this.read_pos = 0;
                 }
-                total_out += n;
-                in_wr.send(buf_out_read[..n].to_vec()).await?;
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                None => return Poll::Ready(Ok(())),
             }
         }
+        let n = buf.remaining().min(this.read_buf.len() - *this.read_pos);
+        buf.put_slice(&this.read_buf[*this.read_pos..*this.read_pos + n]);
+        *this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<St, Si> AsyncWrite for StreamSinkIo<St, Si>
+where
+    Si: Sink<Vec<u8>, Error = Error>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        ready!(this.sink.as_mut().poll_ready(cx)).map_err(to_io_error)?;
+        this.sink.start_send(buf.to_vec()).map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
     }
 
-    info!("shutdown from in (in {}/out {})", total_in, total_out);
-    Ok(())
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().sink.poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().sink.poll_close(cx).map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_stream_sink_io() {
+        let (tx, rx) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+        let stream =
+            futures::stream::iter(vec![Ok(b"hel".to_vec()), Ok(vec![]), Ok(b"lo".to_vec())]);
+        let sink = tx.sink_map_err(|e| anyhow!("{:?}", e));
+        let mut io = StreamSinkIo::new(stream, sink);
+
+        let mut read = vec![];
+        io.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"hello");
+
+        io.write_all(b"world").await.unwrap();
+        io.shutdown().await.unwrap();
+        assert_eq!(rx.collect::<Vec<_>>().await, vec![b"world".to_vec()]);
+    }
 }
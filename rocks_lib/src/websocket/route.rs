@@ -0,0 +1,245 @@
+// Routing of WebSocket upgrade requests. The first rule matching the request
+// decides whether the session is refused, which users it accepts, how its
+// targets are told about the client and how many such sessions may be open.
+
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{http::RequestHead, OutboundProxyProtocolConfig, VlessUsers, WsRouteConfig};
+
+/// A route with what it settles for sessions.
+struct Route {
+    config: WsRouteConfig,
+    users: Arc<VlessUsers>,
+    outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    /// Room for sessions, for routes with `max_connections`.
+    slots: Option<Arc<Semaphore>>,
+}
+
+/// The routes of a ws inbound, with the users each of them accepts.
+pub struct WsRoutes {
+    routes: Vec<Route>,
+    users: Arc<VlessUsers>,
+}
+
+/// The settings of a session a route lets through.
+pub(crate) struct WsSession {
+    pub(crate) users: Arc<VlessUsers>,
+    /// Replaces the outbound PROXY header setting of the inbound, if set.
+    pub(crate) outbound_proxy_protocol: Option<Arc<OutboundProxyProtocolConfig>>,
+    /// Holds a place among the sessions of a limited route while it lives.
+    pub(crate) slot: Option<OwnedSemaphorePermit>,
+}
+
+/// What the routes make of an upgrade request.
+pub(crate) enum Routed {
+    Session(WsSession),
+    /// A rule rejects the request.
+    Rejected,
+    /// The matching route has as many sessions open as it takes.
+    Full,
+}
+
+/// `host` without its port, if it has one.
+fn strip_port(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or(host, |(ip, _)| ip),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// Whether `path` is `want` or under it, taking whole segments only.
+fn path_matches(want: &str, path: &str) -> bool {
+    path.strip_prefix(want)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || want.ends_with('/'))
+}
+
+fn matches(route: &WsRouteConfig, request: &RequestHead) -> bool {
+    let path = route
+        .path
+        .as_deref()
+        .is_none_or(|want| path_matches(want, request.path()));
+    let host = route.host.as_deref().is_none_or(|want| {
+        request
+            .header("host")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .is_some_and(|got| strip_port(got).eq_ignore_ascii_case(strip_port(want)))
+    });
+    let headers = route
+        .headers
+        .iter()
+        .all(|(name, want)| request.header(name) == Some(want.as_bytes()));
+    path && host && headers
+}
+
+impl WsRoutes {
+    /// Routes picking among `users`, the users of the inbound.
+    pub fn new(routes: Vec<WsRouteConfig>, users: Arc<VlessUsers>) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|config| {
+                let users = match &config.users {
+                    Some(ids) => Arc::new(users.only(ids)),
+                    None => users.clone(),
+                };
+                let outbound_proxy_protocol = config.outbound_proxy_protocol.clone().map(Arc::new);
+                let slots = config.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+                Route {
+                    config,
+                    users,
+                    outbound_proxy_protocol,
+                    slots,
+                }
+            })
+            .collect();
+        Self { routes, users }
+    }
+
+    /// The settings of the session `request` asks for, unless it is refused.
+    pub(crate) fn route(&self, request: &RequestHead) -> Routed {
+        let Some(route) = self.routes.iter().find(|r| matches(&r.config, request)) else {
+            return Routed::Session(WsSession {
+                users: self.users.clone(),
+                outbound_proxy_protocol: None,
+                slot: None,
+            });
+        };
+        if route.config.reject {
+            return Routed::Rejected;
+        }
+        let slot = match &route.slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => return Routed::Full,
+            },
+            None => None,
+        };
+        Routed::Session(WsSession {
+            users: route.users.clone(),
+            outbound_proxy_protocol: route.outbound_proxy_protocol.clone(),
+            slot,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{user_id_from_str, IpCidr, ProxyVersion, VlessUser};
+
+    fn route(path: Option<&str>, host: Option<&str>, headers: &[(&str, &str)]) -> WsRouteConfig {
+        WsRouteConfig {
+            path: path.map(String::from),
+            host: host.map(String::from),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            reject: false,
+            users: None,
+            outbound_proxy_protocol: None,
+            max_connections: None,
+        }
+    }
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:443"), "::1");
+        assert_eq!(strip_port("[::1]"), "::1");
+    }
+
+    #[test]
+    fn test_route() {
+        let alice = user_id_from_str("alice").unwrap();
+        let relay = OutboundProxyProtocolConfig {
+            version: ProxyVersion::V1,
+            to: vec![IpCidr::try_from("10.0.0.0/8".to_string()).unwrap()],
+        };
+        let users = Arc::new(VlessUsers::new(
+            ["alice", "bob"].map(|id| id.parse::<VlessUser>().unwrap()),
+        ));
+        let routes = WsRoutes::new(
+            vec![
+                WsRouteConfig {
+                    users: Some(vec![alice]),
+                    outbound_proxy_protocol: Some(relay.clone()),
+                    ..route(Some("/a"), Some("a.example"), &[("X-Token", "t")])
+                },
+                WsRouteConfig {
+                    reject: true,
+                    ..route(Some("/a"), None, &[])
+                },
+            ],
+            users,
+        );
+        let len = |r: Routed| match r {
+            Routed::Session(session) => Some(session.users.len()),
+            _ => None,
+        };
+
+        let matching = request("/a/b?ed=1", &[("host", "A.example:443"), ("x-token", "t")]);
+        let Routed::Session(session) = routes.route(&matching) else {
+            panic!("matching request refused");
+        };
+        assert_eq!(session.users.len(), 1);
+        assert_eq!(session.outbound_proxy_protocol.as_deref(), Some(&relay));
+        let wrong_token = request("/a", &[("Host", "a.example"), ("X-Token", "u")]);
+        assert_eq!(len(routes.route(&wrong_token)), None);
+        let other_path = request("/b", &[("Host", "a.example"), ("X-Token", "t")]);
+        assert_eq!(len(routes.route(&other_path)), Some(2));
+        // Paths match by whole segments.
+        for path in ["/admin", "/a2"] {
+            let longer = request(path, &[("Host", "a.example"), ("X-Token", "t")]);
+            assert_eq!(len(routes.route(&longer)), Some(2), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("/a", "/a"));
+        assert!(path_matches("/a", "/a/"));
+        assert!(path_matches("/a", "/a/b"));
+        assert!(!path_matches("/a", "/admin"));
+        assert!(!path_matches("/a", "/"));
+        assert!(path_matches("/a/", "/a/b"));
+        assert!(!path_matches("/a/", "/a"));
+        assert!(path_matches("/", "/anything"));
+    }
+
+    #[test]
+    fn test_route_max_connections() {
+        let users = Arc::new(VlessUsers::new(["alice".parse::<VlessUser>().unwrap()]));
+        let routes = WsRoutes::new(
+            vec![WsRouteConfig {
+                max_connections: Some(1),
+                ..route(Some("/limited"), None, &[])
+            }],
+            users,
+        );
+        let limited = request("/limited", &[]);
+        let Routed::Session(first) = routes.route(&limited) else {
+            panic!("first session refused");
+        };
+        assert!(matches!(routes.route(&limited), Routed::Full));
+        assert!(matches!(
+            routes.route(&request("/other", &[])),
+            Routed::Session(_)
+        ));
+        drop(first);
+        assert!(matches!(routes.route(&limited), Routed::Session(_)));
+    }
+}
@@ -1,14 +1,24 @@
+use std::task::ready;
+
 use tokio::io::AsyncWrite;
 
 pub trait WriteExt {
     fn with(self, f: impl FnMut(&[u8]) -> Vec<u8> + Unpin) -> impl AsyncWrite;
 }
 
+/// Writes `f(buf)` for every `buf` written to it.
+///
+/// The transformed buffer is written out completely before the original
+/// length is reported back, so callers such as `write_all` see the number of
+/// bytes they handed in rather than the transformed size.
 #[pin_project::pin_project]
 struct WithWrite<W, F> {
     #[pin]
     inner: W,
     f: F,
+    pending: Vec<u8>,
+    written: usize,
+    consumed: usize,
 }
 
 impl<W> WriteExt for W
@@ -16,7 +26,13 @@ where
     W: AsyncWrite,
 {
     fn with(self, f: impl FnMut(&[u8]) -> Vec<u8> + Unpin) -> impl AsyncWrite {
-        WithWrite { inner: self, f }
+        WithWrite {
+            inner: self,
+            f,
+            pending: vec![],
+            written: 0,
+            consumed: 0,
+        }
     }
 }
 
@@ -30,8 +46,24 @@ where
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        let this = self.project();
-        this.inner.poll_write(cx, (this.f)(buf).as_slice())
+        let mut this = self.project();
+        if this.pending.is_empty() {
+            *this.pending = (this.f)(buf);
+            *this.written = 0;
+            *this.consumed = buf.len();
+        }
+        while *this.written < this.pending.len() {
+            let n = ready!(this
+                .inner
+                .as_mut()
+                .poll_write(cx, &this.pending[*this.written..]))?;
+            if n == 0 {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            *this.written += n;
+        }
+        this.pending.clear();
+        std::task::Poll::Ready(Ok(*this.consumed))
     }
     fn poll_flush(
         self: std::pin::Pin<&mut Self>,
@@ -1,31 +1,276 @@
-use rocks_lib::{run_vless_over_tcp, run_vless_over_tungstenite_ws};
-use tokio::select;
-use tracing::info;
-use warp::Filter;
+use std::{
+    collections::HashMap, net::SocketAddr, os::fd::AsRawFd, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 
-async fn wrap() -> Result<(), Box<dyn std::error::Error>> {
-    let root = warp::path::end().and(warp::fs::dir("public"));
+use clap::{Args, Parser, Subcommand};
+use rocks_lib::{
+    drain, run_inbound, run_site, upgrade, user_id_from_str, Config, Handover, InboundTransport,
+    ListenAddr, Listener, ListenerRole,
+};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+};
+use tracing::{error, info, Level};
+use uuid::Uuid;
 
-    warp::serve(root).run(([127, 0, 0, 1], 8888)).await;
+const DEFAULT_CONFIG: &str = "rocks.toml";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Lightweight proxy server")]
+struct Cli {
+    /// Maximum level of log messages to print.
+    #[arg(long, global = true, default_value_t = Level::INFO)]
+    log_level: Level,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Start the server. On SIGUSR2 it starts its binary anew, hands the new
+    /// server its listeners and exits once its connections are over.
+    Run(RunArgs),
+    /// Load and validate the configuration, then exit.
+    CheckConfig(ConfigArgs),
+    /// Print a user id: a random v4 UUID, or the UUID NAME maps to.
+    Uuid {
+        /// Map this string to a user id, the same way `xray uuid -i` does.
+        name: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+struct ConfigArgs {
+    /// Path of the configuration file.
+    #[arg(short, long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+
+    /// Override the listen address of an inbound, can be repeated. ADDR may
+    /// be a Unix socket, as `unix:/path/to/socket`.
+    #[arg(long = "listen", value_name = "TAG=ADDR", value_parser = parse_listen_override)]
+    listen: Vec<(String, ListenAddr)>,
+
+    /// Override the listen address of the static site.
+    #[arg(long, value_name = "ADDR")]
+    site_listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// After handing over to a new server, how many seconds connections
+    /// still open have to finish.
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    drain_timeout: u64,
+}
+
+fn parse_listen_override(s: &str) -> Result<(String, ListenAddr), String> {
+    let (tag, addr) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TAG=ADDR, got `{}`", s))?;
+    let addr = addr.parse()?;
+    Ok((tag.to_string(), addr))
+}
+
+impl ConfigArgs {
+    fn load(&self) -> Result<Config, anyhow::Error> {
+        let mut config = Config::load(&self.config)?;
+        for (tag, listen) in &self.listen {
+            config.override_listen(tag, listen.clone())?;
+        }
+        if let Some(listen) = self.site_listen {
+            let site = config
+                .site
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("--site-listen given but no [site] configured"))?;
+            site.listen = Some(listen);
+        }
+        Ok(config)
+    }
+}
+
+async fn run(config: Config, drain_timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let users = Arc::new(config.vless_users());
+
+    // Listeners come from the server this one replaces, then from systemd,
+    // which names them after their inbounds, and are bound here otherwise.
+    let (handover, mut handed_over) = match Handover::receive()? {
+        Some((handover, listeners)) => (Some(handover), listeners),
+        None => (None, HashMap::new()),
+    };
+    let mut activated = Listener::activated()?;
+    let mut listeners = vec![];
+    for inbound in &config.inbounds {
+        let listener = match handed_over.remove(&ListenerRole::Inbound(inbound.tag.clone())) {
+            Some(listener) => Some(listener),
+            None => activated.remove(&inbound.tag),
+        };
+        let listener = match listener {
+            Some(listener) => Some(listener),
+            // QUIC binds a UDP socket of its own.
+            None if matches!(inbound.transport, InboundTransport::Quic) => None,
+            None => Some(Listener::bind(&inbound.listen, inbound.listen_mode).await?),
+        };
+        listeners.push(listener);
+    }
+    if let Some(name) = activated.keys().next() {
+        return Err(format!(
+            "no inbound tagged `{}` for the socket passed by systemd",
+            name
+        )
+        .into());
+    }
+    let site_listener = match config.site.as_ref().and_then(|site| site.listen) {
+        Some(listen) => match handed_over.remove(&ListenerRole::Site) {
+            Some(listener) => Some(listener),
+            None => Some(Listener::bind(&ListenAddr::Inet(listen), None).await?),
+        },
+        None => None,
+    };
+    for role in handed_over.keys() {
+        info!("closing {:?}, which is no longer configured", role);
+    }
+
+    // What a new server would take over from this one.
+    let mut fds = vec![];
+    let mut quic = vec![];
+    for (inbound, listener) in config.inbounds.iter().zip(&listeners) {
+        match listener {
+            Some(listener) => fds.push((
+                ListenerRole::Inbound(inbound.tag.clone()),
+                listener.as_raw_fd(),
+            )),
+            None => quic.push(inbound.tag.clone()),
+        }
+    }
+    // A QUIC socket cannot go to the new server with its connections, as
+    // they live in this process.
+    let quic = quic.join(", ");
+    if !quic.is_empty() {
+        info!(
+            "SIGUSR2 will not upgrade this server: QUIC inbound(s) {}",
+            quic
+        );
+    }
+    if let Some(listener) = &site_listener {
+        fds.push((ListenerRole::Site, listener.as_raw_fd()));
+    }
+
+    let mut inbounds = JoinSet::new();
+    for (inbound, listener) in config.inbounds.into_iter().zip(listeners) {
+        let tag = inbound.tag.clone();
+        let users = users.clone();
+        let site = config.site.clone();
+        inbounds.spawn(async move { (tag, run_inbound(inbound, listener, users, site).await) });
+    }
+    let mut site = tokio::spawn(async move {
+        match (config.site, site_listener) {
+            (Some(site), Some(listener)) => run_site(site.root, listener).await,
+            _ => std::future::pending().await,
+        }
+    });
+    let mut upgrades = signal(SignalKind::user_defined2())?;
+    if let Some(handover) = handover {
+        handover.ready()?;
+    }
+
+    loop {
+        select!(
+            Some(r) = inbounds.join_next() => {
+                info!("inbound finished: {:?}", r);
+                return Ok(());
+            },
+            r = &mut site => {
+                info!("site finished: {:?}", r);
+                return Ok(());
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("Ctrl-C received");
+                return Ok(());
+            },
+            _ = upgrades.recv() => {
+                if !quic.is_empty() {
+                    error!(
+                        "not upgrading: QUIC inbound(s) {} cannot be handed over, restart instead",
+                        quic
+                    );
+                    continue;
+                }
+                match upgrade(&fds).await {
+                    Ok(()) => break,
+                    Err(e) => info!("upgrade failed, serving on: {:?}", e),
+                }
+            }
+        );
+    }
+
+    // The new server is accepting now; let the sessions here finish.
+    info!("handed over to the new server, draining connections");
+    inbounds.abort_all();
+    site.abort();
+    select!(
+        _ = drain(drain_timeout) => {},
+        _ = tokio::signal::ctrl_c() => info!("Ctrl-C received, not waiting for the connections"),
+    );
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level)
+        .init();
 
-    select!(
-        r = run_vless_over_tcp() => {
-            info!("test_vless finished: {:?}", r);
-        },
-        r = run_vless_over_tungstenite_ws() => {
-            info!("test_vless finished: {:?}", r);
-        },
-        r = wrap() => {
-            info!("wrap finished: {:?}", r);
-        },
-        _ = tokio::signal::ctrl_c() => info!("Ctrl-C received")
-    );
+    match cli.command {
+        Command::Run(args) => {
+            let drain_timeout = Duration::from_secs(args.drain_timeout);
+            run(args.config.load()?, drain_timeout).await?
+        }
+        Command::CheckConfig(args) => {
+            let config = args.load()?;
+            for inbound in &config.inbounds {
+                let tls = match &inbound.tls {
+                    Some(tls) => {
+                        tls.server_config()?;
+                        "+tls"
+                    }
+                    None => "",
+                };
+                println!(
+                    "inbound {}: {:?}/{:?}{} on {}",
+                    inbound.tag, inbound.protocol, inbound.transport, tls, inbound.listen
+                );
+            }
+            if config
+                .inbounds
+                .iter()
+                .any(|inbound| matches!(inbound.transport, InboundTransport::Quic))
+            {
+                println!("QUIC inbounds: SIGUSR2 will not upgrade, restart instead");
+            }
+            if let Some(site) = &config.site {
+                match site.listen {
+                    Some(listen) => println!("site: {} on {}", site.root.display(), listen),
+                    None => println!("site: {} through fallbacks only", site.root.display()),
+                }
+            }
+            println!("{} user(s), configuration OK", config.users.len());
+        }
+        Command::Uuid { name } => {
+            let id = match name {
+                Some(name) => user_id_from_str(&name)?,
+                None => Uuid::new_v4(),
+            };
+            println!("{}", id);
+        }
+    }
 
     Ok(())
 }